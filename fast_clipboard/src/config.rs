@@ -1,9 +1,14 @@
-use crate::store::Key;
-use log::info;
+use crate::eviction::EvictionPolicy;
+use crate::key::{self, KeyError, WrappedKey};
+use crate::migrate::{self, Schema};
+use crate::sensitive::{PasswordHintPolicy, SensitivePolicy};
+use crate::store::{generate_encryption_key, get_clipboard, ClipboardStorage, Key, StorageFormat};
+use anyhow::anyhow;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::default::Default;
-use std::fs::{self, OpenOptions};
-use std::io::{Read, Write};
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
 const CONFIG_FILE_NAME: &str = "config.json";
@@ -35,11 +40,179 @@ impl ConfigFile {
             config: Config::default(),
         }
     }
+
+    /// The directory holding the config and the clipboard history
+    pub fn dir(&self) -> PathBuf {
+        self.path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+}
+
+impl ConfigFile {
+    /// Returns the encryption key, generating one on first start
+    /// A new key is written to key_path and recorded in the config
+    pub fn load_or_create_key(&mut self, key_path: &Path) -> anyhow::Result<Key> {
+        if self.config.key_protection == KeyProtection::Passphrase {
+            return Err(KeyError::PassphraseRequired.into());
+        }
+        if self.config.key_path.is_some() {
+            return Ok(self.config.get_key()?);
+        }
+        if key_path.exists() {
+            info!("Using existing key file at {:?}", key_path);
+        } else {
+            info!("No key configured. Creating one at {:?}", key_path);
+            key::write_key_file(key_path, &generate_encryption_key())?;
+        }
+        self.config.update_key_path(key_path.to_path_buf());
+        let key = self.config.get_key()?;
+        self.save()?;
+        Ok(key)
+    }
+
+    /// Protects the key with a passphrase, or changes the passphrase
+    /// The wrapped key replaces any plain key file
+    pub fn protect_key(
+        &mut self,
+        key: &Key,
+        passphrase: &str,
+        wrapped_path: &Path,
+    ) -> anyhow::Result<()> {
+        let wrapped = WrappedKey::wrap(key, passphrase)?;
+        key::write_wrapped_key_file(wrapped_path, &wrapped)?;
+
+        let old_path = self.config.key_path.replace(wrapped_path.to_path_buf());
+        let old_protection = self.config.key_protection;
+        self.config.key_protection = KeyProtection::Passphrase;
+        self.save()?;
+
+        if old_protection == KeyProtection::Plain {
+            if let Some(old_path) = old_path.filter(|p| p != wrapped_path) {
+                info!("Removing plain key file at {:?}", old_path);
+                fs::remove_file(old_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Recovers the key protected by a passphrase
+    pub fn unlock_key(&self, passphrase: &str) -> Result<Key, KeyError> {
+        if self.config.key_protection != KeyProtection::Passphrase {
+            return self.config.get_key();
+        }
+        let key_path = self.config.key_path().ok_or(KeyError::NotConfigured)?;
+        key::read_wrapped_key_file(key_path)?.unwrap_key(passphrase)
+    }
+}
+
+impl ConfigFile {
+    /// Re-encrypts the history with a fresh key
+    /// The new key waits next to the old one until the history is rewritten,
+    /// only then does it replace the old key file
+    pub fn rotate_key(
+        &self,
+        store: &mut ClipboardStorage,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let key_path = self.config.key_path().ok_or(KeyError::NotConfigured)?;
+        if self.read_key(key_path, passphrase)? != *store.key() {
+            return Err(anyhow!("the history is not encrypted with {:?}", key_path));
+        }
+        let old_key = *store.key();
+        let new_key = generate_encryption_key();
+        let pending_path = pending_key_path(key_path);
+        let _ = fs::remove_file(&pending_path);
+        self.write_key(&pending_path, &new_key, passphrase)?;
+
+        if let Err(e) = store.rotate_key(new_key) {
+            let _ = fs::remove_file(&pending_path);
+            return Err(e.into());
+        }
+        if let Err(e) = crate::fs::rename(&pending_path, key_path) {
+            error!("Could not replace key file, restoring the old key: {}", e);
+            store.rotate_key(old_key)?;
+            let _ = fs::remove_file(&pending_path);
+            return Err(e.into());
+        }
+        info!("Rotated the key in {:?}", key_path);
+        Ok(())
+    }
+
+    /// Opens the history encrypted with key, limited to clipboard_size entries
+    /// and expiring entries unused for entry_ttl_secs
+    /// Finishes a rotation that was cut short after the history was rewritten,
+    /// or drops the new key of one cut short before
+    pub fn open_history(
+        &self,
+        key: Key,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<ClipboardStorage> {
+        let mut store = self.open_store(key, passphrase)?;
+        store.set_eviction(self.config.clipboard_size, self.config.eviction_policy);
+        store.set_ttl(self.config.entry_ttl());
+        store.set_sensitive_policy(self.config.sensitive_policy);
+        Ok(store)
+    }
+
+    fn open_store(&self, key: Key, passphrase: Option<&str>) -> anyhow::Result<ClipboardStorage> {
+        let dir = self.dir();
+        let format = self.config.storage_format;
+        let pending_path = self.config.key_path().map(pending_key_path);
+        let (key_path, pending_path) = match (self.config.key_path(), pending_path) {
+            (Some(key_path), Some(pending_path)) if pending_path.exists() => {
+                (key_path, pending_path)
+            }
+            _ => return get_clipboard(&dir, key, format).map_err(|e| anyhow!(e.to_string())),
+        };
+        match get_clipboard(&dir, key, format) {
+            Ok(store) => {
+                warn!("Dropping the key of an unfinished rotation");
+                fs::remove_file(&pending_path)?;
+                Ok(store)
+            }
+            Err(e) => {
+                let pending_key = self.read_key(&pending_path, passphrase)?;
+                let store =
+                    get_clipboard(&dir, pending_key, format).map_err(|_| anyhow!(e.to_string()))?;
+                warn!("Finishing an interrupted key rotation");
+                crate::fs::rename(&pending_path, key_path)?;
+                Ok(store)
+            }
+        }
+    }
+
+    fn read_key(&self, path: &Path, passphrase: Option<&str>) -> Result<Key, KeyError> {
+        match self.config.key_protection {
+            KeyProtection::Plain => key::read_key_file(path),
+            KeyProtection::Passphrase => {
+                let passphrase = passphrase.ok_or(KeyError::PassphraseRequired)?;
+                key::read_wrapped_key_file(path)?.unwrap_key(passphrase)
+            }
+        }
+    }
+
+    fn write_key(&self, path: &Path, key: &Key, passphrase: Option<&str>) -> Result<(), KeyError> {
+        match self.config.key_protection {
+            KeyProtection::Plain => key::write_key_file(path, key),
+            KeyProtection::Passphrase => {
+                let passphrase = passphrase.ok_or(KeyError::PassphraseRequired)?;
+                key::write_wrapped_key_file(path, &WrappedKey::wrap(key, passphrase)?)
+            }
+        }
+    }
+}
+
+/// Where a new key waits while the history is re-encrypted
+fn pending_key_path(key_path: &Path) -> PathBuf {
+    crate::fs::with_suffix(key_path, ".new")
 }
 
 impl Storage for ConfigFile {
     /// Loads config file from the dir_path with config.json appended
     /// Creates file if it doesn't exist
+    /// An unreadable file is replaced by its backup generation when there is one
     fn load(&mut self) -> anyhow::Result<()> {
         let dir_path = self.path.parent().unwrap();
         info!("Loading config from: {:?}", dir_path);
@@ -48,48 +221,145 @@ impl Storage for ConfigFile {
             fs::create_dir_all(&dir_path)?;
         }
 
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&self.path)?;
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer)?;
-
+        migrate::upgrade_file(Schema::Config, &self.path)?;
+        let buffer = match fs::read(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            read => read?,
+        };
         if buffer.is_empty() {
             info!("No config file found. Creating at {:?}.", &self.path);
-            buffer = serde_json::to_string(&self.config)?;
-            f.write_all(&buffer.as_bytes())?;
+            return self.save();
         }
-        let config = serde_json::from_str::<Config>(&buffer)?;
-        self.config = config;
+        self.config = match parse_config(&buffer) {
+            Ok(config) => config,
+            Err(e) => {
+                let restored = crate::fs::restore_backup(&self.path, &e.to_string(), parse_config)?;
+                match restored {
+                    Some((config, _)) => config,
+                    None => return Err(e),
+                }
+            }
+        };
         Ok(())
     }
 
+    /// Replaces the file atomically, keeping the previous generation as a backup
     fn save(&self) -> anyhow::Result<()> {
-        let bytes = serde_json::to_vec(&self.config)?;
-        let mut f = OpenOptions::new().write(true).open(&self.path)?;
-
-        f.write_all(&bytes)?;
+        let bytes = serde_json::to_vec(&ConfigDocument {
+            version: Schema::Config.version(),
+            config: self.config.clone(),
+        })?;
+        crate::fs::write_atomic_with_backup(&self.path, &bytes)?;
         Ok(())
     }
 }
 
+/// The content of config.json
+#[derive(Serialize, Deserialize)]
+struct ConfigDocument {
+    version: u64,
+    config: Config,
+}
+
+/// Older versions are upgraded first
+fn parse_config(bytes: &[u8]) -> anyhow::Result<Config> {
+    let (doc, _) = migrate::upgrade(Schema::Config, serde_json::from_slice(bytes)?)?;
+    Ok(serde_json::from_value::<ConfigDocument>(doc)?.config)
+}
+
+/// How the encryption key is stored on disk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyProtection {
+    /// The raw key, readable by the current user
+    #[default]
+    Plain,
+    /// The key wrapped by a passphrase, history starts locked
+    Passphrase,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     pub clipboard_size: usize,
     key_path: Option<PathBuf>,
+    #[serde(default)]
+    key_protection: KeyProtection,
+    #[serde(default)]
+    storage_format: StorageFormat,
+    /// Which entries go once there are more than clipboard_size
+    #[serde(default)]
+    eviction_policy: EvictionPolicy,
+    /// Entries unused for this many seconds expire, None keeps them
+    #[serde(default)]
+    entry_ttl_secs: Option<u64>,
+    /// Whether content that looks like a secret is kept, expired soon or not stored
+    #[serde(default)]
+    sensitive_policy: SensitivePolicy,
+    /// Whether content password managers mark as secret is stored at all
+    #[serde(default)]
+    password_hint_policy: PasswordHintPolicy,
+    #[serde(default)]
+    primary_selection: PrimarySelectionConfig,
+}
+
+/// How the primary selection, pasted with a middle click, is handled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrimarySelectionConfig {
+    /// Whether primary selections are stored in the history
+    pub track: bool,
+    /// Whether the clipboard and the primary selection are kept the same
+    pub sync: bool,
+    /// How long the primary selection has to stay unchanged before it is taken,
+    /// so selections being dragged out aren't stored bit by bit
+    pub debounce_ms: u64,
+}
+
+impl Default for PrimarySelectionConfig {
+    fn default() -> Self {
+        PrimarySelectionConfig {
+            track: false,
+            sync: false,
+            debounce_ms: 500,
+        }
+    }
 }
 
 impl Config {
-    pub fn get_key(&self) -> anyhow::Result<Key> {
-        let key_path = self.key_path.as_ref().unwrap();
-        let mut f = OpenOptions::new().read(true).open(key_path)?;
-        let mut buf = vec![];
-        f.read_to_end(&mut buf)?;
-        return Ok(buf
-            .try_into()
-            .expect("Could not convert buffer into key array"));
+    pub fn get_key(&self) -> Result<Key, KeyError> {
+        let key_path = self.key_path.as_ref().ok_or(KeyError::NotConfigured)?;
+        key::read_key_file(key_path)
+    }
+
+    pub fn key_path(&self) -> Option<&Path> {
+        self.key_path.as_deref()
+    }
+
+    pub fn key_protection(&self) -> KeyProtection {
+        self.key_protection
+    }
+
+    pub fn storage_format(&self) -> StorageFormat {
+        self.storage_format
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy
+    }
+
+    pub fn sensitive_policy(&self) -> SensitivePolicy {
+        self.sensitive_policy
+    }
+
+    pub fn password_hint_policy(&self) -> PasswordHintPolicy {
+        self.password_hint_policy
+    }
+
+    pub fn primary_selection(&self) -> PrimarySelectionConfig {
+        self.primary_selection
+    }
+
+    pub fn entry_ttl(&self) -> Option<chrono::Duration> {
+        self.entry_ttl_secs
+            .map(|secs| chrono::Duration::seconds(secs as i64))
     }
 
     pub fn update_key_path(&mut self, path: PathBuf) {
@@ -100,6 +370,13 @@ impl Config {
         Config {
             clipboard_size,
             key_path: None,
+            key_protection: KeyProtection::Plain,
+            storage_format: StorageFormat::Json,
+            eviction_policy: EvictionPolicy::default(),
+            entry_ttl_secs: None,
+            sensitive_policy: SensitivePolicy::default(),
+            password_hint_policy: PasswordHintPolicy::default(),
+            primary_selection: PrimarySelectionConfig::default(),
         }
     }
 }
@@ -109,6 +386,13 @@ impl Default for Config {
         Config {
             clipboard_size: DEFAULT_CLIPBOARD_SIZE,
             key_path: None,
+            key_protection: KeyProtection::Plain,
+            storage_format: StorageFormat::Json,
+            eviction_policy: EvictionPolicy::default(),
+            entry_ttl_secs: None,
+            sensitive_policy: SensitivePolicy::default(),
+            password_hint_policy: PasswordHintPolicy::default(),
+            primary_selection: PrimarySelectionConfig::default(),
         }
     }
 }
@@ -124,6 +408,7 @@ pub fn get_config(dir_path: &PathBuf) -> anyhow::Result<ConfigFile> {
 mod tests {
     use super::*;
     use std::fs::{File, OpenOptions};
+    use std::io::Write;
 
     fn new_file() -> (File, PathBuf) {
         let tmp_file = temp_file::empty();
@@ -161,4 +446,191 @@ mod tests {
         config_file.load().unwrap();
         assert_eq!(config_file.config.clipboard_size, 300);
     }
+
+    #[test]
+    fn test_config_save_replaces_longer_file() {
+        let (_file, path_buf) = new_file();
+        fs::write(&path_buf, " ".repeat(200)).unwrap();
+        let mut config_file = ConfigFile::new(&path_buf);
+        config_file.config.clipboard_size = 3;
+        config_file.save().unwrap();
+        config_file.load().unwrap();
+        fs::remove_file(crate::fs::backup_path(&path_buf)).unwrap();
+        assert_eq!(config_file.config.clipboard_size, 3);
+    }
+
+    #[test]
+    fn test_config_restores_backup_of_corrupt_file() {
+        let (_file, path_buf) = new_file();
+        let mut config_file = ConfigFile::new(&path_buf);
+        config_file.config.clipboard_size = 300;
+        config_file.save().unwrap();
+        config_file.config.clipboard_size = 400;
+        config_file.save().unwrap();
+        fs::write(&path_buf, "{\"clipboard_si").unwrap();
+
+        let mut config_file = ConfigFile::new(&path_buf);
+        let loaded = config_file.load();
+        let corrupt: Vec<_> = fs::read_dir(config_file.dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.to_string_lossy()
+                    .starts_with(&format!("{}.corrupt-", path_buf.display()))
+            })
+            .collect();
+        for path in &corrupt {
+            fs::remove_file(path).unwrap();
+        }
+        fs::remove_file(crate::fs::backup_path(&path_buf)).unwrap();
+        loaded.unwrap();
+        assert_eq!(config_file.config.clipboard_size, 300);
+        assert_eq!(corrupt.len(), 1);
+    }
+
+    #[test]
+    fn test_config_creates_key_on_first_start() {
+        let (_file, path_buf) = new_file();
+        let key_path = path_buf.with_extension("key");
+        let key = {
+            let mut config_file = ConfigFile::new(&path_buf);
+            config_file.load().unwrap();
+            config_file.load_or_create_key(&key_path).unwrap()
+        };
+        let mut config_file = ConfigFile::new(&path_buf);
+        config_file.load().unwrap();
+        assert_eq!(config_file.config.key_path(), Some(key_path.as_path()));
+        let reloaded = config_file.load_or_create_key(&key_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        assert_eq!(key, reloaded);
+    }
+
+    #[test]
+    fn test_config_refuses_missing_key() {
+        let (_file, path_buf) = new_file();
+        let key_path = path_buf.with_extension("key");
+        let mut config_file = ConfigFile::new(&path_buf);
+        config_file.load().unwrap();
+        config_file.load_or_create_key(&key_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+
+        let err = config_file.load_or_create_key(&key_path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KeyError>(),
+            Some(KeyError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_can_protect_key_with_passphrase() {
+        let (_file, path_buf) = new_file();
+        let key_path = path_buf.with_extension("key");
+        let wrapped_path = path_buf.with_extension("wrapped");
+        let mut config_file = ConfigFile::new(&path_buf);
+        config_file.load().unwrap();
+        let key = config_file.load_or_create_key(&key_path).unwrap();
+        config_file
+            .protect_key(&key, "correct horse", &wrapped_path)
+            .unwrap();
+        assert!(!key_path.exists());
+
+        let mut config_file = ConfigFile::new(&path_buf);
+        config_file.load().unwrap();
+        let plain = config_file.load_or_create_key(&key_path);
+        let wrong = config_file.unlock_key("battery staple");
+        let unlocked = config_file.unlock_key("correct horse");
+        fs::remove_file(&wrapped_path).unwrap();
+        assert_eq!(
+            config_file.config.key_protection(),
+            KeyProtection::Passphrase
+        );
+        assert!(matches!(
+            plain.unwrap_err().downcast_ref::<KeyError>(),
+            Some(KeyError::PassphraseRequired)
+        ));
+        assert!(matches!(wrong, Err(KeyError::WrongPassphrase)));
+        assert_eq!(unlocked.unwrap(), key);
+    }
+
+    fn history_dir() -> (ConfigFile, ClipboardStorage) {
+        let dir = crate::fs::tmp_dir();
+        let mut config_file = get_config(&dir).unwrap();
+        let key = config_file.load_or_create_key(&dir.join("key")).unwrap();
+        let mut store = config_file.open_history(key, None).unwrap();
+        store
+            .ingest(&[1, 2, 3], crate::entry::EntryKind::Text)
+            .unwrap();
+        (config_file, store)
+    }
+
+    #[test]
+    fn test_config_limits_history_to_clipboard_size() {
+        let (mut config_file, store) = history_dir();
+        config_file.config.clipboard_size = 2;
+        config_file.config.eviction_policy = EvictionPolicy::OldestFirst;
+        let mut store = config_file.open_history(*store.key(), None).unwrap();
+        for bytes in [[4], [5], [4]] {
+            store.ingest(&bytes, crate::entry::EntryKind::Text).unwrap();
+        }
+        fs::remove_dir_all(config_file.dir()).unwrap();
+        assert_eq!(store.size().unwrap(), 2);
+        assert_eq!(store.list_entries().unwrap()[0].content(), vec![4]);
+        assert_eq!(store.list_entries().unwrap()[1].content(), vec![5]);
+    }
+
+    #[test]
+    fn test_config_rotate_key_retires_old_key() {
+        let (config_file, mut store) = history_dir();
+        let old_key = *store.key();
+        config_file.rotate_key(&mut store, None).unwrap();
+
+        let key = config_file.config.get_key().unwrap();
+        let reopened = config_file.open_history(key, None);
+        let with_old_key = get_clipboard(&config_file.dir(), old_key, StorageFormat::Json);
+        let pending_exists = config_file.dir().join("key.new").exists();
+        fs::remove_dir_all(config_file.dir()).unwrap();
+        assert_ne!(key, old_key);
+        assert_eq!(&key, store.key());
+        assert_eq!(
+            reopened.unwrap().list_entries().unwrap()[0].content(),
+            vec![1, 2, 3]
+        );
+        assert!(with_old_key.is_err());
+        assert!(!pending_exists);
+    }
+
+    #[test]
+    fn test_config_finishes_interrupted_rotation() {
+        let (config_file, mut store) = history_dir();
+        let old_key = *store.key();
+        // Crash after the history was rewritten but before the key file was replaced
+        let new_key = generate_encryption_key();
+        key::write_key_file(&config_file.dir().join("key.new"), &new_key).unwrap();
+        store.rotate_key(new_key).unwrap();
+
+        let reopened = config_file.open_history(old_key, None);
+        let key = config_file.config.get_key();
+        fs::remove_dir_all(config_file.dir()).unwrap();
+        assert_eq!(
+            reopened.unwrap().list_entries().unwrap()[0].content(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(key.unwrap(), new_key);
+    }
+
+    #[test]
+    fn test_config_drops_key_of_unfinished_rotation() {
+        let (config_file, store) = history_dir();
+        let key = *store.key();
+        let pending_path = config_file.dir().join("key.new");
+        key::write_key_file(&pending_path, &generate_encryption_key()).unwrap();
+
+        let reopened = config_file.open_history(key, None);
+        let pending_exists = pending_path.exists();
+        let configured = config_file.config.get_key();
+        fs::remove_dir_all(config_file.dir()).unwrap();
+        assert_eq!(reopened.unwrap().size().unwrap(), 1);
+        assert!(!pending_exists);
+        assert_eq!(configured.unwrap(), key);
+    }
 }
@@ -0,0 +1,123 @@
+/// Decides which entries make room once the history is full
+use crate::entry::EntryMetadata;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// The entry used longest ago goes first
+    #[default]
+    LeastRecentlyUsed,
+    /// The entry copied first goes first, no matter when it was used
+    OldestFirst,
+    /// The entry used least often goes first, ties go to the one used longest ago
+    LeastFrequentlyUsed,
+    /// Like LeastRecentlyUsed, also evicting while the content and its alternatives
+    /// take more than max_bytes
+    TotalBytes { max_bytes: usize },
+}
+
+impl EvictionPolicy {
+    /// The index of the next entry to evict, None once the entries fit
+    /// Pinned entries are never evicted and don't count towards the limits
+    /// Only the metadata is needed, entries don't have to be decrypted for it
+    pub fn victim<E: AsRef<EntryMetadata>>(
+        &self,
+        entries: &[E],
+        max_entries: usize,
+    ) -> Option<usize> {
+        let unpinned = entries
+            .iter()
+            .map(|entry| entry.as_ref())
+            .enumerate()
+            .filter(|(_, meta)| !meta.pinned);
+        let too_many = unpinned.clone().count() > max_entries;
+        let too_big = match self {
+            EvictionPolicy::TotalBytes { max_bytes } => {
+                unpinned
+                    .clone()
+                    .map(|(_, meta)| meta.stored_size())
+                    .sum::<usize>()
+                    > *max_bytes
+            }
+            _ => false,
+        };
+        if !too_many && !too_big {
+            return None;
+        }
+        // Of equal candidates the one further back goes first
+        let candidates = unpinned.rev();
+        let victim = match self {
+            EvictionPolicy::OldestFirst => candidates.min_by_key(|(_, meta)| meta.created_at),
+            EvictionPolicy::LeastRecentlyUsed | EvictionPolicy::TotalBytes { .. } => {
+                candidates.min_by_key(|(_, meta)| meta.last_used_at)
+            }
+            EvictionPolicy::LeastFrequentlyUsed => {
+                candidates.min_by_key(|(_, meta)| (meta.use_count, meta.last_used_at))
+            }
+        };
+        victim.map(|(idx, _)| idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Entry;
+    use chrono::{Duration, Utc};
+
+    /// Copied at minute created, used again uses times until minute used
+    fn entry(content: &[u8], created: i64, used: i64, uses: u64) -> Entry {
+        let start = Utc::now();
+        serde_json::from_value(serde_json::json!({
+            "bytes": content,
+            "meta": {
+                "created_at": start + Duration::minutes(created),
+                "last_used_at": start + Duration::minutes(used),
+                "kind": "Text",
+                "mime": null,
+                "size": content.len(),
+                "use_count": uses,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_eviction_policies_pick_victim() {
+        let entries = vec![
+            entry(&[1; 8], 0, 3, 1),
+            entry(&[2; 2], 1, 2, 3),
+            entry(&[3; 4], 2, 1, 2),
+        ];
+        let victim = |policy: EvictionPolicy, max_entries| policy.victim(&entries, max_entries);
+        assert_eq!(victim(EvictionPolicy::OldestFirst, 3), None);
+        assert_eq!(victim(EvictionPolicy::OldestFirst, 2), Some(0));
+        assert_eq!(victim(EvictionPolicy::LeastRecentlyUsed, 2), Some(2));
+        assert_eq!(victim(EvictionPolicy::LeastFrequentlyUsed, 2), Some(0));
+        let budget = EvictionPolicy::TotalBytes { max_bytes: 12 };
+        assert_eq!(victim(budget, 3), Some(2));
+        assert_eq!(victim(budget, 2), Some(2));
+        let budget = EvictionPolicy::TotalBytes { max_bytes: 14 };
+        assert_eq!(victim(budget, 3), None);
+    }
+
+    #[test]
+    fn test_eviction_counts_alternatives_towards_total_bytes() {
+        let entries = vec![
+            entry(&[1; 4], 1, 1, 0),
+            entry(&[2; 4], 0, 0, 0).with_alternative("text/html", &[2; 8]),
+        ];
+        let budget = EvictionPolicy::TotalBytes { max_bytes: 12 };
+        assert_eq!(budget.victim(&entries, 2), Some(1));
+        let budget = EvictionPolicy::TotalBytes { max_bytes: 16 };
+        assert_eq!(budget.victim(&entries, 2), None);
+    }
+
+    #[test]
+    fn test_eviction_skips_pinned_entries() {
+        let mut entries = vec![entry(&[1], 1, 1, 0), entry(&[2], 0, 0, 0)];
+        entries[1].set_pinned(true);
+        assert_eq!(EvictionPolicy::OldestFirst.victim(&entries, 1), None);
+        assert_eq!(EvictionPolicy::OldestFirst.victim(&entries, 0), Some(0));
+    }
+}
@@ -0,0 +1,273 @@
+/// Filesystem helpers shared by the stores
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Where the previous generation of a file is kept
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// The path with a suffix appended to its file name, e.g. entries.json.tmp
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replaces the file at path without ever leaving it partially written
+/// The content goes to a temporary file first which is renamed over path
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = write_tmp(path, bytes, 0o666)?;
+    rename(&tmp_path, path)
+}
+
+/// Like write_atomic, for files only the current user may read
+pub fn write_atomic_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = write_tmp(path, bytes, 0o600)?;
+    rename(&tmp_path, path)
+}
+
+/// Like write_atomic, dropping the backup generation before path is replaced
+/// A crash in between leaves the old content without a backup,
+/// never the new content next to a backup that could be restored over it
+pub fn write_atomic_dropping_backup(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = write_tmp(path, bytes, 0o666)?;
+    match fs::remove_file(backup_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    rename(&tmp_path, path)
+}
+
+/// Writes bytes durably to the temporary file next to path, created with mode
+/// A temporary file left by a crash is replaced, it could have another mode
+fn write_tmp(path: &Path, bytes: &[u8], mode: u32) -> io::Result<PathBuf> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let _ = fs::remove_file(&tmp_path);
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp_path)?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    Ok(tmp_path)
+}
+
+/// Like write_atomic, keeping the replaced file as the backup generation
+pub fn write_atomic_with_backup(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if path.exists() {
+        let backup_path = backup_path(path);
+        let tmp_path = with_suffix(&backup_path, ".tmp");
+        let _ = fs::remove_file(&tmp_path);
+        // A link is enough, path itself is only ever replaced, never rewritten
+        if fs::hard_link(path, &tmp_path).is_err() {
+            fs::copy(path, &tmp_path)?;
+        }
+        rename(&tmp_path, &backup_path)?;
+    }
+    write_atomic(path, bytes)
+}
+
+/// What was given up when an unreadable file was replaced by its backup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Recovery {
+    pub path: PathBuf,
+    pub reason: String,
+    /// Where the unreadable file was moved to
+    pub corrupt_path: PathBuf,
+    /// When the restored backup was written, changes after it are lost
+    pub backup_saved_at: Option<DateTime<Utc>>,
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} could not be read ({}) and was moved to {:?}, ",
+            self.path, self.reason, self.corrupt_path
+        )?;
+        match self.backup_saved_at {
+            Some(saved_at) => write!(
+                f,
+                "restored the backup saved at {}, changes made after that are lost",
+                saved_at.to_rfc3339()
+            ),
+            None => write!(f, "restored the backup, changes made after it are lost"),
+        }
+    }
+}
+
+/// Replaces the unreadable file at path with its backup generation
+/// Nothing is touched unless parse accepts the backup
+pub fn restore_backup<T, E>(
+    path: &Path,
+    reason: &str,
+    parse: impl FnOnce(&[u8]) -> Result<T, E>,
+) -> io::Result<Option<(T, Recovery)>> {
+    let backup_path = backup_path(path);
+    let bytes = match fs::read(&backup_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let value = match parse(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    let backup_saved_at = fs::metadata(&backup_path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .map(DateTime::<Utc>::from);
+
+    let corrupt_path = with_suffix(
+        path,
+        &format!(".corrupt-{}", Utc::now().format("%Y%m%dT%H%M%S")),
+    );
+    if path.exists() {
+        rename(path, &corrupt_path)?;
+    }
+    write_atomic(path, &bytes)?;
+    let recovery = Recovery {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+        corrupt_path,
+        backup_saved_at,
+    };
+    warn!("{}", recovery);
+    Ok(Some((value, recovery)))
+}
+
+/// Renames from over to and makes the rename itself durable
+pub fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)?;
+    match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// A new empty directory for a test, the test removes it when done
+#[cfg(test)]
+pub(crate) fn tmp_dir() -> PathBuf {
+    let tmp_file = temp_file::empty();
+    let dir = tmp_file.path().with_extension("d");
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic_replaces_content() {
+        let tmp_file = temp_file::with_contents(b"a much longer old content");
+        write_atomic(tmp_file.path(), b"new").unwrap();
+        assert_eq!(fs::read(tmp_file.path()).unwrap(), b"new");
+        assert!(!with_suffix(tmp_file.path(), ".tmp").exists());
+    }
+
+    #[test]
+    fn test_write_atomic_private_is_only_readable_by_user() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tmp_dir();
+        let path = dir.join("file");
+        fs::write(with_suffix(&path, ".tmp"), b"left by a crash").unwrap();
+        write_atomic_private(&path, b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let content = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(content, b"secret");
+    }
+
+    #[test]
+    fn test_write_atomic_with_backup_keeps_previous_generation() {
+        let dir = tmp_dir();
+        let path = dir.join("file");
+        write_atomic_with_backup(&path, b"first").unwrap();
+        let first_backup = backup_path(&path).exists();
+        write_atomic_with_backup(&path, b"second").unwrap();
+        let current = fs::read(&path).unwrap();
+        let backup = fs::read(backup_path(&path)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!first_backup);
+        assert_eq!(current, b"second");
+        assert_eq!(backup, b"first");
+    }
+
+    #[test]
+    fn test_write_atomic_dropping_backup_removes_previous_generation() {
+        let dir = tmp_dir();
+        let path = dir.join("file");
+        write_atomic_with_backup(&path, b"first").unwrap();
+        write_atomic_with_backup(&path, b"second").unwrap();
+        write_atomic_dropping_backup(&path, b"third").unwrap();
+        let current = fs::read(&path).unwrap();
+        let backup_exists = backup_path(&path).exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(current, b"third");
+        assert!(!backup_exists);
+    }
+
+    #[test]
+    fn test_write_atomic_dropping_backup_drops_backup_first() {
+        let dir = tmp_dir();
+        // A directory can't be renamed over, like a crash before the new content is in place
+        let path = dir.join("file");
+        fs::create_dir_all(path.join("inside")).unwrap();
+        fs::write(backup_path(&path), b"old").unwrap();
+        let written = write_atomic_dropping_backup(&path, b"new");
+        let backup_exists = backup_path(&path).exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(written.is_err());
+        assert!(!backup_exists);
+    }
+
+    #[test]
+    fn test_restore_backup_sets_corrupt_file_aside() {
+        let dir = tmp_dir();
+        let path = dir.join("file");
+        write_atomic_with_backup(&path, b"good").unwrap();
+        write_atomic_with_backup(&path, b"go").unwrap();
+        let (value, recovery) = restore_backup(&path, "truncated", |bytes| {
+            String::from_utf8(bytes.to_vec())
+        })
+        .unwrap()
+        .unwrap();
+        let current = fs::read(&path).unwrap();
+        let corrupt = fs::read(&recovery.corrupt_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(value, "good");
+        assert_eq!(current, b"good");
+        assert_eq!(corrupt, b"go");
+        assert!(recovery.backup_saved_at.is_some());
+    }
+
+    #[test]
+    fn test_restore_backup_leaves_files_when_backup_is_unusable() {
+        let dir = tmp_dir();
+        let path = dir.join("file");
+        write_atomic_with_backup(&path, b"old").unwrap();
+        write_atomic_with_backup(&path, b"new").unwrap();
+        let restored = restore_backup(&path, "wrong key", |_| Err::<(), _>("unusable")).unwrap();
+        let current = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(restored.is_none());
+        assert_eq!(current, b"new");
+    }
+
+    #[test]
+    fn test_with_suffix_keeps_extension() {
+        let path = with_suffix(Path::new("/a/key.wrapped"), ".new");
+        assert_eq!(path, PathBuf::from("/a/key.wrapped.new"));
+    }
+}
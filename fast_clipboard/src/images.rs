@@ -0,0 +1,207 @@
+/// Copied images, kept as PNG whatever format they were offered in
+use crate::entry::{Entry, EntryKind};
+use image::{imageops::FilterType, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Cursor;
+use thiserror::Error;
+
+/// Image mime types in order of preference, all are stored as PNG
+pub const IMAGE_MIME_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/bmp",
+    "image/x-bmp",
+];
+
+pub const PNG_MIME_TYPE: &str = "image/png";
+
+/// Largest width and height of the thumbnails image entries are listed with
+pub const THUMBNAIL_SIZE: u32 = 64;
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("unsupported image type: {0}")]
+    Unsupported(String),
+    #[error("error decoding the image: {0}")]
+    Decode(String),
+    #[error("error encoding the image: {0}")]
+    Encode(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl fmt::Display for Dimensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// A small RGBA rendering of an image, 4 bytes per pixel row by row
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub dimensions: Dimensions,
+    pub rgba: Vec<u8>,
+}
+
+/// An entry as clients list it, image entries come with a thumbnail instead of their content
+/// Reads as a plain Entry for clients that don't show thumbnails
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ListedEntry {
+    #[serde(flatten)]
+    pub entry: Entry,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl ListedEntry {
+    /// Image entries leave their content out for the thumbnail, text entries keep it
+    pub fn new(entry: &Entry, thumbnail: Option<Thumbnail>) -> Self {
+        match entry.kind() {
+            EntryKind::Image => ListedEntry {
+                entry: entry.without_content(),
+                thumbnail,
+            },
+            EntryKind::Text => ListedEntry {
+                entry: entry.clone(),
+                thumbnail: None,
+            },
+        }
+    }
+}
+
+pub fn is_image(mime: &str) -> bool {
+    IMAGE_MIME_TYPES.contains(&mime)
+}
+
+/// An image entry for bytes offered as mime, converted to PNG if needed
+pub fn image_entry(bytes: &[u8], mime: &str) -> Result<Entry, ImageError> {
+    let (png, dimensions) = to_png(bytes, mime)?;
+    Ok(Entry::new(&png, EntryKind::Image)
+        .with_mime(PNG_MIME_TYPE)
+        .with_dimensions(dimensions))
+}
+
+/// The image as PNG with its dimensions, PNGs are kept as they are
+pub fn to_png(bytes: &[u8], mime: &str) -> Result<(Vec<u8>, Dimensions), ImageError> {
+    let format = format(mime)?;
+    let image = image::load_from_memory_with_format(bytes, format)
+        .map_err(|e| ImageError::Decode(e.to_string()))?;
+    let dimensions = Dimensions {
+        width: image.width(),
+        height: image.height(),
+    };
+    if format == ImageFormat::Png {
+        return Ok((bytes.to_vec(), dimensions));
+    }
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .map_err(|e| ImageError::Encode(e.to_string()))?;
+    Ok((png, dimensions))
+}
+
+/// The PNG scaled down to fit into max_size in both directions, keeping its aspect ratio
+/// Images already small enough are not scaled up
+pub fn thumbnail(png: &[u8], max_size: u32) -> Result<Thumbnail, ImageError> {
+    let image = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|e| ImageError::Decode(e.to_string()))?;
+    let image = if image.width() > max_size || image.height() > max_size {
+        image.resize(max_size, max_size, FilterType::Triangle)
+    } else {
+        image
+    };
+    let rgba = image.into_rgba8();
+    Ok(Thumbnail {
+        dimensions: Dimensions {
+            width: rgba.width(),
+            height: rgba.height(),
+        },
+        rgba: rgba.into_raw(),
+    })
+}
+
+fn format(mime: &str) -> Result<ImageFormat, ImageError> {
+    match mime {
+        "image/png" => Ok(ImageFormat::Png),
+        "image/jpeg" => Ok(ImageFormat::Jpeg),
+        "image/gif" => Ok(ImageFormat::Gif),
+        "image/bmp" | "image/x-bmp" => Ok(ImageFormat::Bmp),
+        other => Err(ImageError::Unsupported(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbaImage};
+
+    fn encoded(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_image_entry_converts_to_png() {
+        let bmp = encoded(40, 20, ImageOutputFormat::Bmp);
+        let entry = image_entry(&bmp, "image/bmp").unwrap();
+        assert_eq!(entry.kind(), EntryKind::Image);
+        assert_eq!(entry.metadata().mime.as_deref(), Some(PNG_MIME_TYPE));
+        assert_eq!(
+            entry.metadata().dimensions,
+            Some(Dimensions {
+                width: 40,
+                height: 20
+            })
+        );
+        assert!(entry.content().starts_with(b"\x89PNG"));
+
+        let png = encoded(3, 2, ImageOutputFormat::Png);
+        assert_eq!(image_entry(&png, "image/png").unwrap().content(), png);
+        assert!(matches!(
+            image_entry(&png, "image/tiff"),
+            Err(ImageError::Unsupported(_))
+        ));
+        assert!(matches!(
+            image_entry(&[1, 2, 3], "image/png"),
+            Err(ImageError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_thumbnail_fits_into_max_size() {
+        let thumbnail = thumbnail(&encoded(200, 100, ImageOutputFormat::Png), 64).unwrap();
+        assert_eq!(thumbnail.dimensions.to_string(), "64x32");
+        assert_eq!(thumbnail.rgba.len(), 64 * 32 * 4);
+        let small = super::thumbnail(&encoded(10, 20, ImageOutputFormat::Png), 64).unwrap();
+        assert_eq!(small.dimensions.to_string(), "10x20");
+    }
+
+    #[test]
+    fn test_listed_image_entry_carries_thumbnail_instead_of_content() {
+        let png = encoded(200, 100, ImageOutputFormat::Png);
+        let entry = image_entry(&png, "image/png").unwrap();
+        let thumbnail = thumbnail(entry.content(), THUMBNAIL_SIZE).unwrap();
+        let listed = ListedEntry::new(&entry, Some(thumbnail.clone()));
+        assert!(listed.entry.content().is_empty());
+        assert_eq!(listed.entry.metadata(), entry.metadata());
+        assert_eq!(listed.thumbnail, Some(thumbnail));
+
+        let json = serde_json::to_string(&listed).unwrap();
+        assert_eq!(serde_json::from_str::<ListedEntry>(&json).unwrap(), listed);
+        let plain: Entry = serde_json::from_str(&json).unwrap();
+        assert_eq!(plain.id(), entry.id());
+
+        let text = Entry::new(b"text", EntryKind::Text);
+        assert_eq!(ListedEntry::new(&text, None).entry, text);
+    }
+}
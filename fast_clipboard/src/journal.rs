@@ -0,0 +1,384 @@
+/// Append-only storage for clipboard entries
+/// Every change is appended as its own encrypted record, so a new copy
+/// doesn't rewrite the whole history. The records are compacted into a
+/// single snapshot record once there are enough of them.
+use crate::entry::{Entry, Key};
+use crate::fs::{self, Recovery};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const JOURNAL_FILE_NAME: &str = "entries.journal";
+
+/// Records appended before the journal is rewritten as a snapshot
+const COMPACT_AFTER: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("error accessing the journal: {0}")]
+    Io(#[from] io::Error),
+    #[error("journal record {record} is unreadable: {reason}")]
+    Corrupt { record: usize, reason: String },
+    #[error("error encoding a journal record: {0}")]
+    Encode(String),
+}
+
+/// A change to the list of entries
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum JournalOp {
+    /// Replaces every entry, only ever the first record
+    Snapshot(Vec<Entry>),
+    /// Inserts an entry at the front
+    Add(Entry),
+    Remove(usize),
+    /// Marks an entry used and moves it to the front
+    MoveToFront {
+        index: usize,
+        at: DateTime<Utc>,
+    },
+    Pin {
+        index: usize,
+        pinned: bool,
+    },
+}
+
+impl JournalOp {
+    pub fn apply(self, entries: &mut Vec<Entry>) -> Result<(), String> {
+        match self {
+            JournalOp::Snapshot(snapshot) => *entries = snapshot,
+            JournalOp::Add(entry) => entries.insert(0, entry),
+            JournalOp::Remove(index) if index < entries.len() => {
+                entries.remove(index);
+            }
+            JournalOp::MoveToFront { index, at } if index < entries.len() => {
+                let mut entry = entries.remove(index);
+                entry.touch(at);
+                entries.insert(0, entry);
+            }
+            JournalOp::Pin { index, pinned } if index < entries.len() => {
+                entries[index].set_pinned(pinned);
+            }
+            JournalOp::Remove(index)
+            | JournalOp::MoveToFront { index, .. }
+            | JournalOp::Pin { index, .. } => return Err(format!("no entry at index {}", index)),
+        }
+        Ok(())
+    }
+}
+
+/// One line of the journal
+#[derive(Serialize, Deserialize)]
+struct Record {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// Binds a record to its position, so records can't be dropped or reordered
+fn associated_data(record: usize) -> Vec<u8> {
+    format!("journal:{}", record).into_bytes()
+}
+
+fn encrypt_record(op: &JournalOp, record: usize, key: &Key) -> Result<Vec<u8>, JournalError> {
+    let plaintext = serde_json::to_vec(op).map_err(|e| JournalError::Encode(e.to_string()))?;
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = associated_data(record);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|e| JournalError::Encode(e.to_string()))?;
+    let mut line = serde_json::to_vec(&Record {
+        nonce: nonce.as_slice().into(),
+        ciphertext,
+    })
+    .map_err(|e| JournalError::Encode(e.to_string()))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn decrypt_record(line: &[u8], record: usize, key: &Key) -> Result<JournalOp, JournalError> {
+    let corrupt = |reason: String| JournalError::Corrupt { record, reason };
+    let parsed = serde_json::from_slice::<Record>(line).map_err(|e| corrupt(e.to_string()))?;
+    if parsed.nonce.len() != 12 {
+        return Err(corrupt("malformed nonce".to_string()));
+    }
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let aad = associated_data(record);
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&parsed.nonce),
+            Payload {
+                msg: &parsed.ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|e| corrupt(e.to_string()))?;
+    serde_json::from_slice(&plaintext).map_err(|e| corrupt(e.to_string()))
+}
+
+/// The entries a journal holds
+struct Replayed {
+    entries: Vec<Entry>,
+    records: usize,
+    /// Length of the journal without a torn last record
+    len: usize,
+}
+
+/// Only a record followed by a newline was completely written,
+/// anything after the last newline is dropped
+fn replay_bytes(bytes: &[u8], key: &Key) -> Result<Replayed, JournalError> {
+    let mut entries = vec![];
+    let mut records = 0;
+    let mut len = 0;
+    while let Some(end) = bytes[len..].iter().position(|b| *b == b'\n') {
+        let op = decrypt_record(&bytes[len..len + end], records, key)?;
+        op.apply(&mut entries)
+            .map_err(|reason| JournalError::Corrupt {
+                record: records,
+                reason,
+            })?;
+        records += 1;
+        len += end + 1;
+    }
+    Ok(Replayed {
+        entries,
+        records,
+        len,
+    })
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    /// Records in the file, the next record's position
+    records: usize,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Self {
+        Journal {
+            path: path.to_path_buf(),
+            records: 0,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replays every record, None if nothing was written yet
+    /// A torn last record left by a crash is cut off
+    pub fn replay(&mut self, key: &Key) -> Result<Option<Vec<Entry>>, JournalError> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) if !bytes.is_empty() => bytes,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let replayed = replay_bytes(&bytes, key)?;
+        if replayed.len < bytes.len() {
+            warn!(
+                "dropping {} bytes of an unfinished journal record",
+                bytes.len() - replayed.len
+            );
+            let f = OpenOptions::new().write(true).open(&self.path)?;
+            f.set_len(replayed.len as u64)?;
+            f.sync_all()?;
+        }
+        debug!("replayed {} journal records", replayed.records);
+        self.records = replayed.records;
+        Ok(Some(replayed.entries))
+    }
+
+    /// Appends records for ops
+    pub fn append(&mut self, ops: &[JournalOp], key: &Key) -> Result<(), JournalError> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut lines = vec![];
+        for (i, op) in ops.iter().enumerate() {
+            lines.extend(encrypt_record(op, self.records + i, key)?);
+        }
+        let mut f = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        let len = f.metadata()?.len();
+        if let Err(e) = f.write_all(&lines).and_then(|_| f.sync_data()) {
+            // Records written after a partial one would never be replayed
+            f.set_len(len)?;
+            return Err(e.into());
+        }
+        self.records += ops.len();
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.records > COMPACT_AFTER
+    }
+
+    /// Rewrites the journal as a single snapshot of entries
+    /// The replaced journal is kept as the backup generation
+    pub fn compact(&mut self, entries: &[Entry], key: &Key) -> Result<(), JournalError> {
+        let snapshot = encrypt_record(&JournalOp::Snapshot(entries.to_vec()), 0, key)?;
+        fs::write_atomic_with_backup(&self.path, &snapshot)?;
+        self.records = 1;
+        info!("compacted journal into {} entries", entries.len());
+        Ok(())
+    }
+
+    /// Like compact, dropping the backup instead of replacing it
+    pub fn replace(&mut self, entries: &[Entry], key: &Key) -> Result<(), JournalError> {
+        let snapshot = encrypt_record(&JournalOp::Snapshot(entries.to_vec()), 0, key)?;
+        fs::write_atomic_dropping_backup(&self.path, &snapshot)?;
+        self.records = 1;
+        Ok(())
+    }
+
+    /// Replaces an unreadable journal with its backup generation
+    pub fn restore_backup(
+        &mut self,
+        reason: &str,
+        key: &Key,
+    ) -> Result<Option<(Vec<Entry>, Recovery)>, JournalError> {
+        let restored = fs::restore_backup(&self.path, reason, |bytes| replay_bytes(bytes, key))?;
+        Ok(restored.map(|(replayed, recovery)| {
+            self.records = replayed.records;
+            (replayed.entries, recovery)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::EntryKind;
+
+    const KEY: &Key = b"Thisisakeyof32bytesThisisakeyof3";
+
+    fn entry(bytes: &[u8]) -> Entry {
+        Entry::new(bytes, EntryKind::Text)
+    }
+
+    fn tmp_journal() -> (PathBuf, Journal) {
+        let dir = fs::tmp_dir();
+        let journal = Journal::new(&dir.join(JOURNAL_FILE_NAME));
+        (dir, journal)
+    }
+
+    fn contents(entries: &[Entry]) -> Vec<&[u8]> {
+        entries.iter().map(Entry::content).collect()
+    }
+
+    #[test]
+    fn test_journal_replays_appended_ops() {
+        let (dir, mut journal) = tmp_journal();
+        assert_eq!(journal.replay(KEY).unwrap(), None);
+        journal
+            .append(
+                &[JournalOp::Add(entry(&[1])), JournalOp::Add(entry(&[2]))],
+                KEY,
+            )
+            .unwrap();
+        journal
+            .append(
+                &[
+                    JournalOp::MoveToFront {
+                        index: 1,
+                        at: Utc::now(),
+                    },
+                    JournalOp::Remove(1),
+                ],
+                KEY,
+            )
+            .unwrap();
+
+        let mut reopened = Journal::new(journal.path());
+        let entries = reopened.replay(KEY).unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents(&entries), vec![&[1]]);
+        assert_eq!(reopened.records, 4);
+    }
+
+    #[test]
+    fn test_journal_compacts_into_snapshot() {
+        let (dir, mut journal) = tmp_journal();
+        journal
+            .append(
+                &[JournalOp::Add(entry(&[1])), JournalOp::Add(entry(&[2]))],
+                KEY,
+            )
+            .unwrap();
+        let entries = journal.replay(KEY).unwrap().unwrap();
+        journal.compact(&entries, KEY).unwrap();
+        journal.append(&[JournalOp::Remove(0)], KEY).unwrap();
+
+        let mut reopened = Journal::new(journal.path());
+        let replayed = reopened.replay(KEY).unwrap().unwrap();
+        let backup = std::fs::read(fs::backup_path(journal.path())).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents(&replayed), vec![&[1]]);
+        assert_eq!(reopened.records, 2);
+        assert_eq!(replay_bytes(&backup, KEY).unwrap().records, 2);
+    }
+
+    #[test]
+    fn test_journal_drops_torn_record() {
+        let (dir, mut journal) = tmp_journal();
+        journal.append(&[JournalOp::Add(entry(&[1]))], KEY).unwrap();
+        let torn = encrypt_record(&JournalOp::Add(entry(&[2])), 1, KEY).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(journal.path())
+            .unwrap()
+            .write_all(&torn[..torn.len() / 2])
+            .unwrap();
+
+        let mut reopened = Journal::new(journal.path());
+        let entries = reopened.replay(KEY).unwrap().unwrap();
+        reopened
+            .append(&[JournalOp::Add(entry(&[3]))], KEY)
+            .unwrap();
+        let entries_after_append = Journal::new(journal.path()).replay(KEY).unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents(&entries), vec![&[1]]);
+        assert_eq!(contents(&entries_after_append), vec![&[3], &[1]]);
+    }
+
+    #[test]
+    fn test_journal_detects_reordered_records() {
+        let (dir, mut journal) = tmp_journal();
+        journal
+            .append(
+                &[JournalOp::Add(entry(&[1])), JournalOp::Add(entry(&[2]))],
+                KEY,
+            )
+            .unwrap();
+        let bytes = std::fs::read(journal.path()).unwrap();
+        let mut lines: Vec<&[u8]> = bytes.split_inclusive(|b| *b == b'\n').collect();
+        lines.swap(0, 1);
+        std::fs::write(journal.path(), lines.concat()).unwrap();
+
+        let replayed = Journal::new(journal.path()).replay(KEY);
+        let wrong_key = journal.replay(&[0; 32]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            replayed,
+            Err(JournalError::Corrupt { record: 0, .. })
+        ));
+        assert!(wrong_key.is_err());
+    }
+}
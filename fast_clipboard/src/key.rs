@@ -0,0 +1,287 @@
+/// Reading and writing the key that encrypts clipboard entries
+use crate::store::Key;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const KEY_FILE_NAME: &str = "key";
+pub const WRAPPED_KEY_FILE_NAME: &str = "key.wrapped";
+
+const SALT_LEN: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("no key file is configured")]
+    NotConfigured,
+    #[error("key file {0:?} is missing")]
+    Missing(PathBuf),
+    #[error("key file {path:?} is {len} bytes long, expected {expected}")]
+    WrongLength {
+        path: PathBuf,
+        len: usize,
+        expected: usize,
+    },
+    #[error("error accessing key file {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("key file {path:?} is malformed: {reason}")]
+    Malformed { path: PathBuf, reason: String },
+    #[error("the key is protected by a passphrase")]
+    PassphraseRequired,
+    #[error("the passphrase is wrong")]
+    WrongPassphrase,
+    #[error("error deriving a key from the passphrase: {0}")]
+    Kdf(String),
+}
+
+impl KeyError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        KeyError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+/// Reads a key file, which must hold exactly the raw key bytes
+pub fn read_key_file(path: &Path) -> Result<Key, KeyError> {
+    let mut f = OpenOptions::new().read(true).open(path).map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            KeyError::Missing(path.to_path_buf())
+        } else {
+            KeyError::io(path, e)
+        }
+    })?;
+    let mut buf = vec![];
+    f.read_to_end(&mut buf).map_err(|e| KeyError::io(path, e))?;
+    let len = buf.len();
+    buf.try_into().map_err(|_| KeyError::WrongLength {
+        path: path.to_path_buf(),
+        len,
+        expected: Key::default().len(),
+    })
+}
+
+/// Writes a new key file readable only by the current user
+/// Never overwrites an existing file
+pub fn write_key_file(path: &Path, key: &Key) -> Result<(), KeyError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| KeyError::io(path, e))?;
+    }
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| KeyError::io(path, e))?;
+    f.write_all(key).map_err(|e| KeyError::io(path, e))?;
+    f.sync_all().map_err(|e| KeyError::io(path, e))?;
+    Ok(())
+}
+
+/// Argon2id cost parameters, stored next to the wrapped key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> Result<Key, KeyError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| KeyError::Kdf(e.to_string()))?;
+        let mut derived = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut derived)
+            .map_err(|e| KeyError::Kdf(e.to_string()))?;
+        Ok(derived)
+    }
+
+    /// Binds the parameters to the wrapped key so they can't be downgraded
+    fn associated_data(&self) -> Vec<u8> {
+        format!("argon2id:{}:{}:{}", self.m_cost, self.t_cost, self.p_cost).into_bytes()
+    }
+}
+
+/// The encryption key, encrypted with a key derived from a passphrase
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl WrappedKey {
+    pub fn wrap(key: &Key, passphrase: &str) -> Result<Self, KeyError> {
+        Self::wrap_with(key, passphrase, KdfParams::default())
+    }
+
+    pub fn wrap_with(key: &Key, passphrase: &str, kdf: KdfParams) -> Result<Self, KeyError> {
+        let mut salt = vec![0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = ChaCha20Poly1305::new(&kdf.derive(passphrase, &salt)?.into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = kdf.associated_data();
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: key,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| KeyError::Kdf(e.to_string()))?;
+        Ok(WrappedKey {
+            kdf,
+            salt,
+            nonce: nonce.as_slice().into(),
+            ciphertext,
+        })
+    }
+
+    pub fn unwrap_key(&self, passphrase: &str) -> Result<Key, KeyError> {
+        if self.nonce.len() != 12 {
+            return Err(KeyError::WrongPassphrase);
+        }
+        let cipher = ChaCha20Poly1305::new(&self.kdf.derive(passphrase, &self.salt)?.into());
+        let nonce = Nonce::clone_from_slice(&self.nonce);
+        let aad = self.kdf.associated_data();
+        let key = cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| KeyError::WrongPassphrase)?;
+        key.try_into().map_err(|_| KeyError::WrongPassphrase)
+    }
+}
+
+pub fn read_wrapped_key_file(path: &Path) -> Result<WrappedKey, KeyError> {
+    let buf = fs::read(path).map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            KeyError::Missing(path.to_path_buf())
+        } else {
+            KeyError::io(path, e)
+        }
+    })?;
+    serde_json::from_slice(&buf).map_err(|e| KeyError::Malformed {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+/// Writes a wrapped key readable only by the current user
+/// Replaces an existing file only once the new one is fully written
+pub fn write_wrapped_key_file(path: &Path, wrapped: &WrappedKey) -> Result<(), KeyError> {
+    let bytes = serde_json::to_vec(wrapped).map_err(|e| KeyError::Malformed {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })?;
+    crate::fs::write_atomic_private(path, &bytes).map_err(|e| KeyError::io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::generate_encryption_key;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let tmp_file = temp_file::empty();
+        let path = tmp_file.path().with_extension("key");
+        let key = generate_encryption_key();
+        write_key_file(&path, &key).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let read = read_key_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(read.unwrap(), key);
+    }
+
+    #[test]
+    fn test_key_file_is_never_overwritten() {
+        let tmp_file = temp_file::empty();
+        let err = write_key_file(tmp_file.path(), &generate_encryption_key()).unwrap_err();
+        assert!(matches!(err, KeyError::Io { .. }));
+    }
+
+    #[test]
+    fn test_key_file_missing() {
+        let tmp_file = temp_file::empty();
+        let path = tmp_file.path().with_extension("missing");
+        let err = read_key_file(&path).unwrap_err();
+        assert!(matches!(err, KeyError::Missing(_)));
+    }
+
+    /// Keeps the tests fast, the defaults take a noticeable amount of time
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_wrapped_key_round_trip() {
+        let key = generate_encryption_key();
+        let wrapped = WrappedKey::wrap_with(&key, "correct horse", TEST_KDF).unwrap();
+        assert_eq!(wrapped.unwrap_key("correct horse").unwrap(), key);
+        assert!(matches!(
+            wrapped.unwrap_key("battery staple"),
+            Err(KeyError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_wrapped_key_detects_weakened_params() {
+        let key = generate_encryption_key();
+        let mut wrapped = WrappedKey::wrap_with(&key, "correct horse", TEST_KDF).unwrap();
+        wrapped.kdf.t_cost = 2;
+        assert!(matches!(
+            wrapped.unwrap_key("correct horse"),
+            Err(KeyError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_wrapped_key_file_round_trip() {
+        let tmp_file = temp_file::empty();
+        let key = generate_encryption_key();
+        let wrapped = WrappedKey::wrap_with(&key, "correct horse", TEST_KDF).unwrap();
+        write_wrapped_key_file(tmp_file.path(), &wrapped).unwrap();
+        let mode = fs::metadata(tmp_file.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let read = read_wrapped_key_file(tmp_file.path()).unwrap();
+        assert_eq!(read.unwrap_key("correct horse").unwrap(), key);
+    }
+
+    #[test]
+    fn test_key_file_wrong_length() {
+        let tmp_file = temp_file::with_contents(b"too short");
+        let err = read_key_file(tmp_file.path()).unwrap_err();
+        assert!(matches!(err, KeyError::WrongLength { len: 9, .. }));
+    }
+}
@@ -1,3 +1,13 @@
+pub mod clock;
 pub mod config;
 pub mod entry;
+pub mod eviction;
+pub mod fs;
+pub mod images;
+pub mod journal;
+pub mod key;
+pub mod migrate;
+pub mod search;
+pub mod sensitive;
+pub mod sqlite;
 pub mod store;
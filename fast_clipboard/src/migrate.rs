@@ -0,0 +1,252 @@
+/// Upgrading files written by older versions
+/// Every file is an envelope holding its schema version next to the content.
+/// Files from before the envelope are version 0.
+use crate::fs;
+use log::info;
+use serde_json::{json, Value};
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("{schema} version {found} is newer than the supported version {supported}")]
+    TooNew {
+        schema: &'static str,
+        found: u64,
+        supported: u64,
+    },
+    #[error("{schema} can't be migrated: {reason}")]
+    Malformed {
+        schema: &'static str,
+        reason: String,
+    },
+    #[error("error upgrading {schema}: {source}")]
+    Io {
+        schema: &'static str,
+        source: io::Error,
+    },
+}
+
+/// Upgrades a document from the version matching its index to the next
+type Migration = fn(Value) -> Result<Value, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schema {
+    Entries,
+    Config,
+}
+
+impl Schema {
+    fn name(&self) -> &'static str {
+        match self {
+            Schema::Entries => "clipboard history",
+            Schema::Config => "config",
+        }
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        match self {
+            Schema::Entries => &[entries_v0_to_v1],
+            Schema::Config => &[config_v0_to_v1],
+        }
+    }
+
+    /// The version written by this build
+    pub fn version(&self) -> u64 {
+        self.migrations().len() as u64
+    }
+
+    fn malformed(&self, reason: impl ToString) -> MigrationError {
+        MigrationError::Malformed {
+            schema: self.name(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// The bare list of encrypted entries is wrapped in an envelope
+fn entries_v0_to_v1(doc: Value) -> Result<Value, String> {
+    match doc {
+        Value::Array(_) => Ok(json!({ "version": 1, "entries": doc })),
+        _ => Err("expected a list of entries".to_string()),
+    }
+}
+
+/// The bare config is wrapped in an envelope
+fn config_v0_to_v1(doc: Value) -> Result<Value, String> {
+    match doc {
+        Value::Object(_) => Ok(json!({ "version": 1, "config": doc })),
+        _ => Err("expected a config object".to_string()),
+    }
+}
+
+/// Version 0 documents have no envelope
+fn version_of(schema: Schema, doc: &Value) -> Result<u64, MigrationError> {
+    match doc.get("version") {
+        Some(version) if doc.get(content_field(schema)).is_some() => version
+            .as_u64()
+            .ok_or_else(|| schema.malformed("version is not a number")),
+        _ => Ok(0),
+    }
+}
+
+fn content_field(schema: Schema) -> &'static str {
+    match schema {
+        Schema::Entries => "entries",
+        Schema::Config => "config",
+    }
+}
+
+/// Brings doc up to the current version, returns whether it was older
+pub fn upgrade(schema: Schema, mut doc: Value) -> Result<(Value, bool), MigrationError> {
+    let found = version_of(schema, &doc)?;
+    if found > schema.version() {
+        return Err(MigrationError::TooNew {
+            schema: schema.name(),
+            found,
+            supported: schema.version(),
+        });
+    }
+    for migration in &schema.migrations()[found as usize..] {
+        doc = migration(doc).map_err(|reason| schema.malformed(reason))?;
+    }
+    Ok((doc, found < schema.version()))
+}
+
+/// Upgrades the file at path in place, the older file becomes the backup
+/// A file that isn't JSON is left for its loader to report
+pub fn upgrade_file(schema: Schema, path: &Path) -> Result<bool, MigrationError> {
+    let io_error = |source| MigrationError::Io {
+        schema: schema.name(),
+        source,
+    };
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(io_error(e)),
+    };
+    let doc = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(doc) => doc,
+        Err(_) => return Ok(false),
+    };
+    let from = version_of(schema, &doc)?;
+    let (doc, upgraded) = upgrade(schema, doc)?;
+    if upgraded {
+        let bytes = serde_json::to_vec(&doc).map_err(|e| schema.malformed(e))?;
+        fs::write_atomic_with_backup(path, &bytes).map_err(io_error)?;
+        info!(
+            "Upgraded {} at {:?} from version {} to {}",
+            schema.name(),
+            path,
+            from,
+            schema.version()
+        );
+    }
+    Ok(upgraded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::entry::Key;
+    use crate::store::ClipboardStorage;
+
+    const KEY: &Key = b"Thisisakeyof32bytesThisisakeyof3";
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Copies a fixture into its own directory, so it can be upgraded in place
+    fn copy_fixture(name: &str, file_name: &str) -> std::path::PathBuf {
+        let dir = fs::tmp_dir();
+        std::fs::copy(fixture(name), dir.join(file_name)).unwrap();
+        dir
+    }
+
+    fn load_entries(name: &str) -> (Vec<Vec<u8>>, Value, Value) {
+        let dir = copy_fixture(name, "entries.json");
+        let path = dir.join("entries.json");
+        let mut clipboard = ClipboardStorage::open(&path, KEY.to_owned());
+        clipboard.load().unwrap();
+        let contents = clipboard
+            .list_entries()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.content().to_vec())
+            .collect();
+        let upgraded = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let backup = std::fs::read(fs::backup_path(&path))
+            .map(|bytes| serde_json::from_slice(&bytes).unwrap())
+            .unwrap_or(Value::Null);
+        std::fs::remove_dir_all(&dir).unwrap();
+        (contents, upgraded, backup)
+    }
+
+    #[test]
+    fn test_migrate_entries_fixtures() {
+        let expected = vec![b"second".to_vec(), b"first".to_vec()];
+        for name in ["entries_v0_without_metadata.json", "entries_v0.json"] {
+            let (contents, upgraded, backup) = load_entries(name);
+            let original: Value =
+                serde_json::from_slice(&std::fs::read(fixture(name)).unwrap()).unwrap();
+            assert_eq!(contents, expected, "{}", name);
+            assert_eq!(upgraded["version"], Schema::Entries.version(), "{}", name);
+            // Loading then gives the entries IDs, the backup is the migrated file
+            assert_eq!(upgraded["entries"].as_array().unwrap().len(), 2, "{}", name);
+            assert_eq!(backup["version"], Schema::Entries.version(), "{}", name);
+            assert_eq!(backup["entries"], original, "{}", name);
+        }
+        let (contents, upgraded, backup) = load_entries("entries_v1.json");
+        let original: Value =
+            serde_json::from_slice(&std::fs::read(fixture("entries_v1.json")).unwrap()).unwrap();
+        assert_eq!(contents, expected);
+        assert_eq!(upgraded["version"], 1);
+        assert_eq!(backup, original);
+    }
+
+    #[test]
+    fn test_migrate_config_fixtures() {
+        for name in [
+            "config_v0_baseline.json",
+            "config_v0.json",
+            "config_v1.json",
+        ] {
+            let doc: Value =
+                serde_json::from_slice(&std::fs::read(fixture(name)).unwrap()).unwrap();
+            let (upgraded, _) = upgrade(Schema::Config, doc).unwrap();
+            assert_eq!(upgraded["version"], Schema::Config.version(), "{}", name);
+            let config: Config = serde_json::from_value(upgraded["config"].clone()).unwrap();
+            assert_eq!(config.clipboard_size, 20, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_migrate_config_file_in_place() {
+        let dir = copy_fixture("config_v0_baseline.json", "config.json");
+        let path = dir.join("config.json");
+        let config_file = crate::config::get_config(&dir).unwrap();
+        let upgraded: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let backup = std::fs::read(fs::backup_path(&path)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config_file.config.clipboard_size, 20);
+        assert_eq!(upgraded["version"], Schema::Config.version());
+        assert_eq!(
+            backup,
+            std::fs::read(fixture("config_v0_baseline.json")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer_versions() {
+        let doc = json!({ "version": Schema::Entries.version() + 1, "entries": [] });
+        assert!(matches!(
+            upgrade(Schema::Entries, doc),
+            Err(MigrationError::TooNew { .. })
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+/// Fuzzy search over clipboard entries
+use crate::entry::{Entry, EntryKind};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use serde::{Deserialize, Serialize};
+
+/// An entry matching a search query
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SearchMatch {
+    pub entry: Entry,
+    /// Higher is a better match
+    pub score: i64,
+    /// Char indices of the text that matched the query
+    pub positions: Vec<usize>,
+}
+
+/// Up to limit text entries matching query, best match first
+/// The query is smart-case: it only matches case sensitively if it has an uppercase letter
+/// An empty query matches every entry in history order
+/// Sensitive entries only match on their mask, their content can't be probed
+pub fn search(entries: &[Entry], query: &str, limit: usize) -> Vec<SearchMatch> {
+    let matcher = SkimMatcherV2::default().smart_case();
+    let mut matches: Vec<SearchMatch> = entries
+        .iter()
+        .filter(|entry| entry.kind() == EntryKind::Text)
+        .filter_map(|entry| {
+            let text = match entry.sensitive() {
+                Some(_) => entry.to_string(),
+                None => String::from_utf8_lossy(entry.content()).into_owned(),
+            };
+            let (score, positions) = if query.is_empty() {
+                (0, vec![])
+            } else {
+                matcher.fuzzy_indices(&text, query)?
+            };
+            Some(SearchMatch {
+                entry: entry.clone(),
+                score,
+                positions,
+            })
+        })
+        .collect();
+    // Stable, equal matches stay in history order
+    matches.sort_by_key(|m| std::cmp::Reverse(m.score));
+    matches.truncate(limit);
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensitive::SensitiveKind;
+
+    fn text(s: &str) -> Entry {
+        Entry::new(s.as_bytes(), EntryKind::Text)
+    }
+
+    fn found(matches: &[SearchMatch]) -> Vec<&[u8]> {
+        matches.iter().map(|m| m.entry.content()).collect()
+    }
+
+    #[test]
+    fn test_search_ranks_matches() {
+        let entries = vec![
+            text("c-a-r-g-o"),
+            text("cargo build --release"),
+            Entry::new(b"cargo", EntryKind::Image),
+            text("git commit"),
+            text("cargo"),
+        ];
+        let matches = search(&entries, "cargo", 10);
+        assert_eq!(
+            found(&matches),
+            [b"cargo build --release".as_ref(), b"cargo", b"c-a-r-g-o"]
+        );
+        assert!(matches[1].score > matches[2].score);
+        assert_eq!(matches[1].positions, [0, 1, 2, 3, 4]);
+        assert_eq!(matches[2].positions, [0, 2, 4, 6, 8]);
+        assert_eq!(found(&search(&entries, "gcm", 10)), [b"git commit"]);
+        assert_eq!(search(&entries, "cargo", 1).len(), 1);
+        assert_eq!(search(&entries, "", 10).len(), 4);
+    }
+
+    #[test]
+    fn test_search_matches_sensitive_entries_on_their_mask() {
+        let entries = vec![
+            text("hunter2").with_sensitive(SensitiveKind::PasswordManager),
+            text("hunter"),
+        ];
+        assert!(search(&entries, "hunter2", 10).is_empty());
+        assert_eq!(found(&search(&entries, "hunt", 10)), [b"hunter"]);
+        assert_eq!(found(&search(&entries, "password", 10)), [b"hunter2"]);
+        assert_eq!(search(&entries, "", 10).len(), 2);
+    }
+
+    #[test]
+    fn test_search_is_smart_case() {
+        let entries = vec![text("Hello World"), text("hello world")];
+        assert_eq!(search(&entries, "hw", 10).len(), 2);
+        assert_eq!(found(&search(&entries, "HW", 10)), [b"Hello World"]);
+    }
+}
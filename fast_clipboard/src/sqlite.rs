@@ -0,0 +1,599 @@
+/// SQLite storage for clipboard entries
+/// Every entry is its own encrypted row, looked up and changed by its ID,
+/// so the history is never read as a whole unless it is listed whole.
+use crate::entry::{EncryptedEntry, Entry, EntryError, EntryId, EntryMetadata, Key, Selection};
+use crate::journal::JournalOp;
+use crate::migrate::MigrationError;
+use crate::search::{self, SearchMatch};
+use crate::store::{ClipboardStorageError, EntryIndex, StorageBackend};
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use rusqlite::{params, Connection, OptionalExtension, Params, Transaction};
+use std::path::{Path, PathBuf};
+
+pub const SQLITE_FILE_NAME: &str = "entries.sqlite";
+
+/// Kept as the database's user_version, which is 0 until the first write
+/// Version 2 added the columns entries are found by
+const SCHEMA_VERSION: u64 = 2;
+
+/// The entry_id and fingerprint columns repeat what the row's metadata and content say,
+/// the fingerprint is keyed so it doesn't reveal the content
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        nonce BLOB NOT NULL,
+        ciphertext BLOB NOT NULL,
+        meta TEXT,
+        entry_id TEXT,
+        fingerprint BLOB
+    );
+    CREATE INDEX IF NOT EXISTS entries_by_position ON entries (position);
+    CREATE INDEX IF NOT EXISTS entries_by_entry_id ON entries (entry_id);
+    CREATE INDEX IF NOT EXISTS entries_by_fingerprint ON entries (fingerprint);";
+
+/// Entries are ordered by position, the front entry has the lowest
+#[derive(Debug)]
+pub struct SqliteStorage {
+    path: PathBuf,
+    /// Opened on first use
+    connection: Option<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn new(path: &Path) -> Self {
+        SqliteStorage {
+            path: path.to_path_buf(),
+            connection: None,
+        }
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection, ClipboardStorageError> {
+        if self.connection.is_none() {
+            let connection = Connection::open(&self.path)?;
+            // Removed rows are overwritten, entries of a retired key can't be read back
+            connection.pragma_update(None, "secure_delete", true)?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// 0 if nothing was written yet, errors for databases of newer versions
+    fn version(&mut self) -> Result<u64, ClipboardStorageError> {
+        let version: u64 = self
+            .connection()?
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(MigrationError::TooNew {
+                schema: "clipboard database",
+                found: version,
+                supported: SCHEMA_VERSION,
+            }
+            .into());
+        }
+        Ok(version)
+    }
+
+    /// Runs f in a transaction on an initialized database
+    /// Databases of older versions are upgraded first, which needs the key of their entries
+    fn transaction<F>(&mut self, key: &Key, f: F) -> Result<(), ClipboardStorageError>
+    where
+        F: FnOnce(&Transaction) -> Result<(), ClipboardStorageError>,
+    {
+        let version = self.version()?;
+        let tx = self.connection()?.transaction()?;
+        if version == 1 {
+            upgrade_v1(&tx, key)?;
+        }
+        tx.execute_batch(CREATE_TABLES)?;
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        f(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    fn read(&mut self, key: &Key) -> Result<Option<Vec<Entry>>, ClipboardStorageError> {
+        if self.version()? == 0 {
+            return Ok(None);
+        }
+        let rows = query(
+            self.connection()?,
+            "SELECT nonce, ciphertext, meta FROM entries ORDER BY position",
+            [],
+        )?;
+        debug!("read {} clipboard entries from the database", rows.len());
+        decrypt(rows, key).map(Some)
+    }
+
+    fn write(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError> {
+        self.transaction(key, |tx| replace_rows(tx, entries, key))
+    }
+
+    /// Changes are made by ID as they happen, see EntryIndex, nothing is left to record
+    fn record(
+        &mut self,
+        _ops: &[JournalOp],
+        _entries: &[Entry],
+        _key: &Key,
+    ) -> Result<(), ClipboardStorageError> {
+        Ok(())
+    }
+
+    /// Filtered on the metadata, only the entries of the page are decrypted
+    fn page(
+        &mut self,
+        offset: usize,
+        limit: usize,
+        selection: Option<Selection>,
+        key: &Key,
+    ) -> Result<Option<Vec<Entry>>, ClipboardStorageError> {
+        if self.version()? == 0 {
+            return Ok(Some(vec![]));
+        }
+        let rows = query(
+            self.connection()?,
+            "SELECT nonce, ciphertext, meta FROM entries
+                WHERE ?3 IS NULL OR IFNULL(json_extract(meta, '$.selection'), ?4) = ?3
+                ORDER BY position LIMIT ?1 OFFSET ?2",
+            params![
+                limit as i64,
+                offset as i64,
+                selection.map(selection_name),
+                selection_name(Selection::default())
+            ],
+        )?;
+        decrypt(rows, key).map(Some)
+    }
+
+    fn index(&mut self) -> Option<&mut dyn EntryIndex> {
+        Some(self)
+    }
+}
+
+impl EntryIndex for SqliteStorage {
+    fn open(&mut self, key: &Key) -> Result<(), ClipboardStorageError> {
+        self.transaction(key, |_| Ok(()))?;
+        // Every entry is encrypted with the same key, the front one tells whether it is key
+        let front = query(
+            self.connection()?,
+            "SELECT nonce, ciphertext, meta FROM entries ORDER BY position LIMIT 1",
+            [],
+        )?;
+        decrypt(front, key)?;
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<usize, ClipboardStorageError> {
+        let len: i64 = self
+            .connection()?
+            .query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0))?;
+        Ok(len as usize)
+    }
+
+    /// Read from the meta column, no entry is decrypted
+    fn metadata(&mut self) -> Result<Vec<EntryMetadata>, ClipboardStorageError> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare("SELECT meta FROM entries ORDER BY position")?;
+        let rows = statement.query_map([], |row| row.get::<_, Option<String>>(0))?;
+        rows.map(|meta| {
+            let meta = meta?.ok_or_else(|| {
+                ClipboardStorageError::Serialization("entry stored without metadata".to_string())
+            })?;
+            serde_json::from_str(&meta)
+                .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))
+        })
+        .collect()
+    }
+
+    fn get(&mut self, id: EntryId, key: &Key) -> Result<Option<Entry>, ClipboardStorageError> {
+        let rows = query(
+            self.connection()?,
+            "SELECT nonce, ciphertext, meta FROM entries WHERE entry_id = ?1",
+            [id.to_string()],
+        )?;
+        Ok(decrypt(rows, key)?.pop())
+    }
+
+    fn find_copy(
+        &mut self,
+        entry: &Entry,
+        key: &Key,
+    ) -> Result<Option<EntryId>, ClipboardStorageError> {
+        let id: Option<String> = self
+            .connection()?
+            .query_row(
+                "SELECT entry_id FROM entries WHERE fingerprint = ?1 LIMIT 1",
+                [entry.fingerprint(key)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        id.map(|id| {
+            EntryId::from_string(&id)
+                .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))
+        })
+        .transpose()
+    }
+
+    /// Decrypts only the text entries
+    fn search(
+        &mut self,
+        query: &str,
+        limit: usize,
+        key: &Key,
+    ) -> Result<Vec<SearchMatch>, ClipboardStorageError> {
+        let rows = self::query(
+            self.connection()?,
+            "SELECT nonce, ciphertext, meta FROM entries
+                WHERE json_extract(meta, '$.kind') = 'Text' ORDER BY position",
+            [],
+        )?;
+        Ok(search::search(&decrypt(rows, key)?, query, limit))
+    }
+
+    fn add(&mut self, entry: &Entry, key: &Key) -> Result<(), ClipboardStorageError> {
+        self.transaction(key, |tx| {
+            let front = front_position(tx)?;
+            insert(tx, front - 1, entry, key)
+        })
+    }
+
+    fn move_to_front(
+        &mut self,
+        id: EntryId,
+        at: DateTime<Utc>,
+        key: &Key,
+    ) -> Result<(), ClipboardStorageError> {
+        self.transaction(key, |tx| {
+            let row = row_of(tx, id)?;
+            let front = front_position(tx)?;
+            update(tx, row, key, |entry| entry.touch(at))?;
+            tx.execute(
+                "UPDATE entries SET position = ?2 WHERE id = ?1",
+                params![row, front - 1],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn set_pinned(
+        &mut self,
+        id: EntryId,
+        pinned: bool,
+        key: &Key,
+    ) -> Result<(), ClipboardStorageError> {
+        self.transaction(key, |tx| {
+            let row = row_of(tx, id)?;
+            update(tx, row, key, |entry| entry.set_pinned(pinned))
+        })
+    }
+
+    fn remove(&mut self, ids: &[EntryId], key: &Key) -> Result<(), ClipboardStorageError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.transaction(key, |tx| {
+            for id in ids {
+                let row = row_of(tx, *id)?;
+                tx.execute("DELETE FROM entries WHERE id = ?1", [row])?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// How the selection is kept in the metadata, which has none if it was written before selections
+fn selection_name(selection: Selection) -> String {
+    serde_json::to_value(selection)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn query<P: Params>(
+    connection: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Vec<EncryptedEntry>, ClipboardStorageError> {
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map(params, |row| {
+        Ok(EncryptedEntry {
+            nonce: row.get(0)?,
+            ciphertext: row.get(1)?,
+            meta: row.get(2)?,
+            kind: None,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+fn decrypt(rows: Vec<EncryptedEntry>, key: &Key) -> Result<Vec<Entry>, ClipboardStorageError> {
+    Ok(rows
+        .into_iter()
+        .map(|row| row.try_into_entry(key))
+        .collect::<Result<Vec<Entry>, EntryError>>()?)
+}
+
+fn insert(
+    tx: &Transaction,
+    position: i64,
+    entry: &Entry,
+    key: &Key,
+) -> Result<(), ClipboardStorageError> {
+    let encrypted = entry.encode(key)?;
+    tx.execute(
+        "INSERT INTO entries (position, nonce, ciphertext, meta, entry_id, fingerprint)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            position,
+            encrypted.nonce,
+            encrypted.ciphertext,
+            encrypted.meta,
+            entry.id().to_string(),
+            entry.fingerprint(key)
+        ],
+    )?;
+    Ok(())
+}
+
+fn replace_rows(
+    tx: &Transaction,
+    entries: &[Entry],
+    key: &Key,
+) -> Result<(), ClipboardStorageError> {
+    tx.execute("DELETE FROM entries", [])?;
+    for (position, entry) in entries.iter().enumerate() {
+        insert(tx, position as i64, entry, key)?;
+    }
+    Ok(())
+}
+
+/// The position in front of every entry is one less than this
+fn front_position(tx: &Transaction) -> Result<i64, ClipboardStorageError> {
+    Ok(
+        tx.query_row("SELECT IFNULL(MIN(position), 1) FROM entries", [], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+/// The row the entry with id is kept in
+fn row_of(tx: &Transaction, id: EntryId) -> Result<i64, ClipboardStorageError> {
+    tx.query_row(
+        "SELECT id FROM entries WHERE entry_id = ?1",
+        [id.to_string()],
+        |row| row.get(0),
+    )
+    .optional()?
+    .ok_or_else(|| ClipboardStorageError::InvalidOperation(format!("No entry with id: {}", id)))
+}
+
+/// Changes the entry in row id
+/// The metadata is authenticated with the content, both are written again
+fn update<F>(tx: &Transaction, id: i64, key: &Key, f: F) -> Result<(), ClipboardStorageError>
+where
+    F: FnOnce(&mut Entry),
+{
+    let mut rows = query(
+        tx,
+        "SELECT nonce, ciphertext, meta FROM entries WHERE id = ?1",
+        [id],
+    )?;
+    let mut entry = rows.remove(0).try_into_entry(key)?;
+    f(&mut entry);
+    let encrypted = entry.encode(key)?;
+    tx.execute(
+        "UPDATE entries SET nonce = ?2, ciphertext = ?3, meta = ?4 WHERE id = ?1",
+        params![id, encrypted.nonce, encrypted.ciphertext, encrypted.meta],
+    )?;
+    Ok(())
+}
+
+/// Adds the entry_id and fingerprint columns and fills them in,
+/// giving entries stored without an ID one
+fn upgrade_v1(tx: &Transaction, key: &Key) -> Result<(), ClipboardStorageError> {
+    tx.execute_batch(
+        "ALTER TABLE entries ADD COLUMN entry_id TEXT;
+        ALTER TABLE entries ADD COLUMN fingerprint BLOB;",
+    )?;
+    let rows = {
+        let mut statement = tx.prepare("SELECT id, nonce, ciphertext, meta FROM entries")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                EncryptedEntry {
+                    nonce: row.get(1)?,
+                    ciphertext: row.get(2)?,
+                    meta: row.get(3)?,
+                    kind: None,
+                },
+            ))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    let count = rows.len();
+    for (id, row) in rows {
+        let mut entry = row.try_into_entry(key)?;
+        entry.assign_missing_id();
+        let encrypted = entry.encode(key)?;
+        tx.execute(
+            "UPDATE entries SET nonce = ?2, ciphertext = ?3, meta = ?4, entry_id = ?5,
+                fingerprint = ?6 WHERE id = ?1",
+            params![
+                id,
+                encrypted.nonce,
+                encrypted.ciphertext,
+                encrypted.meta,
+                entry.id().to_string(),
+                entry.fingerprint(key)
+            ],
+        )?;
+    }
+    info!("upgraded {} clipboard entries in the database", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::EntryKind;
+    use crate::fs::tmp_dir;
+    use crate::store::{ClipboardStorage, StorageFormat};
+
+    const KEY: &Key = b"Thisisakeyof32bytesThisisakeyof3";
+
+    #[test]
+    fn test_sqlite_keeps_changes() {
+        let dir = tmp_dir();
+        let path = dir.join(SQLITE_FILE_NAME);
+        let mut clipboard =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Sqlite);
+        clipboard.load().unwrap();
+        for bytes in [[1], [2], [3], [2], [4]] {
+            clipboard.ingest(&bytes, EntryKind::Text).unwrap();
+        }
+        let id = clipboard.list_entries().unwrap()[1].id();
+        clipboard.remove_entry(id).unwrap();
+        let id = clipboard.list_entries().unwrap()[2].id();
+        clipboard.pin(id).unwrap();
+
+        let mut reloaded =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Sqlite);
+        reloaded.load().unwrap();
+        let page = reloaded.page(1, 2, None).unwrap();
+        let entries = reloaded.list_entries().unwrap();
+        let listed = clipboard.list_entries().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries, listed);
+        let contents: Vec<&[u8]> = entries.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, [[4].as_ref(), &[3], &[1]]);
+        assert_eq!(page, entries[1..3]);
+        assert!(entries[2].is_pinned());
+    }
+
+    #[test]
+    fn test_sqlite_pages_through_one_selection() {
+        let dir = tmp_dir();
+        let path = dir.join(SQLITE_FILE_NAME);
+        let mut clipboard =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Sqlite);
+        clipboard.load().unwrap();
+        for (bytes, selection) in [
+            ([1], Selection::Primary),
+            ([2], Selection::Clipboard),
+            ([3], Selection::Primary),
+            ([4], Selection::Primary),
+        ] {
+            let entry = Entry::new(&bytes, EntryKind::Text).with_selection(selection);
+            clipboard.ingest_entry(entry).unwrap();
+        }
+        let primary = clipboard.page(1, 5, Some(Selection::Primary)).unwrap();
+        let copied = clipboard.page(0, 5, Some(Selection::Clipboard)).unwrap();
+        let all = clipboard.page(0, 5, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let contents: Vec<&[u8]> = primary.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, [[3].as_ref(), &[1]]);
+        assert_eq!(copied.len(), 1);
+        assert_eq!(copied[0].content(), [2]);
+        assert_eq!(all.len(), 4);
+    }
+
+    #[test]
+    fn test_sqlite_finds_copies_stored_by_another_instance() {
+        let dir = tmp_dir();
+        let path = dir.join(SQLITE_FILE_NAME);
+        let mut clipboard =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Sqlite);
+        clipboard.load().unwrap();
+        for bytes in [[1], [2], [3]] {
+            clipboard.ingest(&bytes, EntryKind::Text).unwrap();
+        }
+        let id = clipboard.list_entries().unwrap()[2].id();
+
+        let mut reloaded =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Sqlite);
+        reloaded.load().unwrap();
+        reloaded.ingest(&[1], EntryKind::Text).unwrap();
+        let size = reloaded.size().unwrap();
+        let front = clipboard.list_entries().unwrap()[0].clone();
+        let found = clipboard.get_entry(id).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(front.id(), id);
+        assert_eq!(front.metadata().use_count, 1);
+        assert_eq!(found, Some(front));
+    }
+
+    #[test]
+    fn test_sqlite_upgrades_v1_databases() {
+        let dir = tmp_dir();
+        let path = dir.join(SQLITE_FILE_NAME);
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE entries (
+                    id INTEGER PRIMARY KEY,
+                    position INTEGER NOT NULL,
+                    nonce BLOB NOT NULL,
+                    ciphertext BLOB NOT NULL,
+                    meta TEXT
+                );
+                PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        for (position, bytes) in [[2], [1]].iter().enumerate() {
+            let encrypted = Entry::new(bytes, EntryKind::Text).encode(KEY).unwrap();
+            connection
+                .execute(
+                    "INSERT INTO entries (position, nonce, ciphertext, meta)
+                        VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        position as i64,
+                        encrypted.nonce,
+                        encrypted.ciphertext,
+                        encrypted.meta
+                    ],
+                )
+                .unwrap();
+        }
+        drop(connection);
+
+        let mut clipboard =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Sqlite);
+        clipboard.load().unwrap();
+        let entries = clipboard.list_entries().unwrap();
+        let found = clipboard.get_entry(entries[1].id()).unwrap();
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        let size = clipboard.size().unwrap();
+        let front = clipboard.list_entries().unwrap()[0].clone();
+        let version = SqliteStorage::new(&path).version().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let contents: Vec<&[u8]> = entries.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, [[2].as_ref(), &[1]]);
+        assert_eq!(found.as_ref(), Some(&entries[1]));
+        assert_eq!(size, 2);
+        assert_eq!(front.id(), entries[1].id());
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_sqlite_refuses_newer_databases() {
+        let dir = tmp_dir();
+        let path = dir.join(SQLITE_FILE_NAME);
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        let mut storage = SqliteStorage::new(&path);
+        let read = storage.read(KEY);
+        let write = storage.write(&[], KEY);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            read,
+            Err(ClipboardStorageError::UnsupportedVersion(_))
+        ));
+        assert!(write.is_err());
+    }
+}
@@ -1,47 +1,367 @@
-use crate::entry::{EncryptedEntry, Entry, EntryError, EntryKind};
+use crate::clock::{Clock, SystemClock};
+use crate::entry::{
+    EncryptedEntry, Entry, EntryError, EntryId, EntryKind, EntryMetadata, Selection,
+};
+use crate::eviction::EvictionPolicy;
+use crate::fs;
+use crate::journal::{Journal, JournalError, JournalOp, JOURNAL_FILE_NAME};
+use crate::migrate::{self, MigrationError, Schema};
+use crate::search::{self, SearchMatch};
+use crate::sensitive::SensitivePolicy;
+use crate::sqlite::{SqliteStorage, SQLITE_FILE_NAME};
 
 use chacha20poly1305::{
     aead::{KeyInit, OsRng},
     ChaCha20Poly1305,
 };
 
-use log::{debug, info};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use temp_file::TempFile;
 use thiserror::Error;
 
 /// Deals with reading/writing clipboard entries to storage (e.g. a File)
 use std::{
     error::Error,
-    fs::{File, OpenOptions},
+    fmt,
+    fs::File,
     io::{self, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 const DEFAULT_MAX_ENTRIES: usize = 5;
 
+pub const ENTRIES_FILE_NAME: &str = "entries.json";
+
 pub type Key = [u8; 32];
 
+/// Encrypted every history before keys were generated on first start
+const LEGACY_KEY: &Key = b"Thisisakeyof32bytesThisisakeyof3";
+
+/// The content of entries.json
+#[derive(Serialize, Deserialize)]
+struct EntriesDocument {
+    version: u64,
+    entries: Vec<EncryptedEntry>,
+}
+
+/// The order entries are listed in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListOrder {
+    /// Most recently copied or used first
+    #[default]
+    Recent,
+    /// Most frequently and recently used first, see Entry::frecency
+    Frecency,
+}
+
+/// How the history is kept on disk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageFormat {
+    /// Every entry in one file, rewritten on every change
+    #[default]
+    Json,
+    /// Changes appended to a journal, see the journal module
+    Journal,
+    /// One encrypted row per entry in a SQLite database, see the sqlite module
+    Sqlite,
+}
+
+impl StorageFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            StorageFormat::Json => ENTRIES_FILE_NAME,
+            StorageFormat::Journal => JOURNAL_FILE_NAME,
+            StorageFormat::Sqlite => SQLITE_FILE_NAME,
+        }
+    }
+
+    pub const ALL: [StorageFormat; 3] = [
+        StorageFormat::Json,
+        StorageFormat::Journal,
+        StorageFormat::Sqlite,
+    ];
+}
+
+/// Where the entries are persisted
+pub trait StorageBackend: fmt::Debug + Send {
+    /// Reads every stored entry, None if nothing was stored yet
+    fn read(&mut self, key: &Key) -> Result<Option<Vec<Entry>>, ClipboardStorageError>;
+
+    /// Writes every entry, keeping the replaced ones as the backup generation if there is one
+    fn write(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError>;
+
+    /// Whether record makes use of the ops, otherwise they aren't kept
+    fn records_ops(&self) -> bool {
+        false
+    }
+
+    /// Persists the changes ops made to entries
+    fn record(
+        &mut self,
+        _ops: &[JournalOp],
+        entries: &[Entry],
+        key: &Key,
+    ) -> Result<(), ClipboardStorageError> {
+        self.write(entries, key)
+    }
+
+    /// Writes without keeping a backup and drops the existing one,
+    /// for when the old content must not be recoverable
+    fn replace(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError> {
+        self.write(entries, key)
+    }
+
+    /// Only a backup readable with key replaces the stored entries
+    fn restore_backup(
+        &mut self,
+        _reason: &str,
+        _key: &Key,
+    ) -> Result<Option<(Vec<Entry>, fs::Recovery)>, ClipboardStorageError> {
+        Ok(None)
+    }
+
+    /// Reads limit entries starting at offset without reading the others,
+    /// only counting those copied from selection if there is one
+    /// None if the backend can't and the loaded entries have to be used
+    fn page(
+        &mut self,
+        _offset: usize,
+        _limit: usize,
+        _selection: Option<Selection>,
+        _key: &Key,
+    ) -> Result<Option<Vec<Entry>>, ClipboardStorageError> {
+        Ok(None)
+    }
+
+    /// Set for backends that are the source of truth for the entries, see EntryIndex
+    fn index(&mut self) -> Option<&mut dyn EntryIndex> {
+        None
+    }
+}
+
+/// A backend that looks up and changes entries by ID itself,
+/// so the entries are never loaded and every change goes straight to storage
+pub trait EntryIndex {
+    /// Prepares the stored entries for use, failing if they can't be read with key
+    fn open(&mut self, key: &Key) -> Result<(), ClipboardStorageError>;
+
+    fn len(&mut self) -> Result<usize, ClipboardStorageError>;
+
+    fn is_empty(&mut self) -> Result<bool, ClipboardStorageError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The metadata of every entry in order, enough to pick entries to evict or expire
+    fn metadata(&mut self) -> Result<Vec<EntryMetadata>, ClipboardStorageError>;
+
+    fn get(&mut self, id: EntryId, key: &Key) -> Result<Option<Entry>, ClipboardStorageError>;
+
+    /// The ID of the entry with the same content and alternatives as entry
+    fn find_copy(
+        &mut self,
+        entry: &Entry,
+        key: &Key,
+    ) -> Result<Option<EntryId>, ClipboardStorageError>;
+
+    fn search(
+        &mut self,
+        query: &str,
+        limit: usize,
+        key: &Key,
+    ) -> Result<Vec<SearchMatch>, ClipboardStorageError>;
+
+    /// Inserts the entry at the front
+    fn add(&mut self, entry: &Entry, key: &Key) -> Result<(), ClipboardStorageError>;
+
+    /// Marks the entry used at and moves it to the front
+    fn move_to_front(
+        &mut self,
+        id: EntryId,
+        at: DateTime<Utc>,
+        key: &Key,
+    ) -> Result<(), ClipboardStorageError>;
+
+    fn set_pinned(
+        &mut self,
+        id: EntryId,
+        pinned: bool,
+        key: &Key,
+    ) -> Result<(), ClipboardStorageError>;
+
+    /// Removes the entries, failing for any of ids that isn't stored
+    fn remove(&mut self, ids: &[EntryId], key: &Key) -> Result<(), ClipboardStorageError>;
+}
+
+/// Opens the backend keeping the history at path in format
+pub fn open_backend(path: &Path, format: StorageFormat) -> Box<dyn StorageBackend> {
+    match format {
+        StorageFormat::Json => Box::new(JsonFile::Path(path.to_path_buf())),
+        StorageFormat::Journal => Box::new(Journal::new(path)),
+        StorageFormat::Sqlite => Box::new(SqliteStorage::new(path)),
+    }
+}
+
+/// Every entry in one JSON document
+#[derive(Debug)]
+pub enum JsonFile {
+    /// An open file, rewritten in place
+    File(File),
+    /// A file replaced atomically on every save
+    /// The replaced content is kept as the backup generation
+    Path(PathBuf),
+}
+
+impl StorageBackend for JsonFile {
+    fn read(&mut self, key: &Key) -> Result<Option<Vec<Entry>>, ClipboardStorageError> {
+        let buf = match self {
+            JsonFile::File(f) => {
+                let mut buf = vec![];
+                f.seek(SeekFrom::Start(0))?;
+                f.read_to_end(&mut buf)?;
+                buf
+            }
+            JsonFile::Path(path) => {
+                migrate::upgrade_file(Schema::Entries, path)?;
+                match std::fs::read(path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+                    read => read?,
+                }
+            }
+        };
+        debug!("load buf: [{}]", String::from_utf8_lossy(&buf));
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        parse_entries(&buf, key).map(Some)
+    }
+
+    fn write(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError> {
+        match self {
+            JsonFile::File(f) => {
+                let bytes = serialize_entries(entries, key)?;
+                f.set_len(0)?;
+                f.seek(SeekFrom::Start(0))?;
+                f.write_all(&bytes)?;
+                f.flush()?;
+            }
+            JsonFile::Path(path) => {
+                fs::write_atomic_with_backup(path, &serialize_entries(entries, key)?)?
+            }
+        }
+        Ok(())
+    }
+
+    fn replace(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError> {
+        match self {
+            JsonFile::File(_) => self.write(entries, key)?,
+            JsonFile::Path(path) => {
+                fs::write_atomic_dropping_backup(path, &serialize_entries(entries, key)?)?
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_backup(
+        &mut self,
+        reason: &str,
+        key: &Key,
+    ) -> Result<Option<(Vec<Entry>, fs::Recovery)>, ClipboardStorageError> {
+        match self {
+            JsonFile::File(_) => Ok(None),
+            JsonFile::Path(path) => Ok(fs::restore_backup(path, reason, |bytes| {
+                parse_entries(bytes, key)
+            })?),
+        }
+    }
+}
+
+impl StorageBackend for Journal {
+    fn read(&mut self, key: &Key) -> Result<Option<Vec<Entry>>, ClipboardStorageError> {
+        Ok(self.replay(key)?)
+    }
+
+    fn write(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError> {
+        Ok(self.compact(entries, key)?)
+    }
+
+    fn records_ops(&self) -> bool {
+        true
+    }
+
+    /// Appends the ops, compacting once the journal grew long
+    fn record(
+        &mut self,
+        ops: &[JournalOp],
+        entries: &[Entry],
+        key: &Key,
+    ) -> Result<(), ClipboardStorageError> {
+        self.append(ops, key)?;
+        if self.needs_compaction() {
+            self.compact(entries, key)?;
+        }
+        Ok(())
+    }
+
+    fn replace(&mut self, entries: &[Entry], key: &Key) -> Result<(), ClipboardStorageError> {
+        Ok(Journal::replace(self, entries, key)?)
+    }
+
+    fn restore_backup(
+        &mut self,
+        reason: &str,
+        key: &Key,
+    ) -> Result<Option<(Vec<Entry>, fs::Recovery)>, ClipboardStorageError> {
+        Ok(Journal::restore_backup(self, reason, key)?)
+    }
+}
+
+/// Keeps the entries for as long as the process runs
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Option<Vec<Entry>>,
+}
+
+impl StorageBackend for MemoryStorage {
+    fn read(&mut self, _key: &Key) -> Result<Option<Vec<Entry>>, ClipboardStorageError> {
+        Ok(self.entries.clone())
+    }
+
+    fn write(&mut self, entries: &[Entry], _key: &Key) -> Result<(), ClipboardStorageError> {
+        self.entries = Some(entries.to_vec());
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct ClipboardStorage {
-    storage: File,
+    storage: Box<dyn StorageBackend>,
     /// ClipboardStorage entries. Stored as a vector because I am uncreative
+    /// Always empty for backends with an EntryIndex, which keep the entries themselves
     entries: Vec<Entry>,
     /// How many entries are allowed in the ClipboardStorage
     /// A new copy will always force the oldest from the clipboard
     max_entries: usize,
+    /// Which entries go once there are more than max_entries
+    eviction: EvictionPolicy,
+    /// How long entries are kept unused, forever if None
+    ttl: Option<Duration>,
+    /// What happens to content that looks like a secret
+    sensitive: SensitivePolicy,
+    clock: Arc<dyn Clock>,
     key: Key,
+    /// Set when loading had to fall back to the backup generation
+    recovery: Option<fs::Recovery>,
+    /// Changes not yet persisted
+    pending: Vec<JournalOp>,
 }
 
+/// Keeps the entries in memory only
 impl Default for ClipboardStorage {
     fn default() -> Self {
-        let tmp = temp_file::empty();
-        let f = File::open(tmp.path()).unwrap();
-        Self {
-            storage: f,
-            entries: vec![],
-            max_entries: DEFAULT_MAX_ENTRIES,
-            key: Key::default(),
-        }
+        Self::with_backend(Box::<MemoryStorage>::default(), Key::default())
     }
 }
 
@@ -57,6 +377,8 @@ pub enum ClipboardStorageError {
     Serialization(String),
     #[error("unknown data store error: {0}")]
     Unknown(String),
+    #[error("{0}")]
+    UnsupportedVersion(String),
 }
 
 impl From<EntryError> for ClipboardStorageError {
@@ -79,120 +401,530 @@ impl From<io::Error> for ClipboardStorageError {
     }
 }
 
+impl From<MigrationError> for ClipboardStorageError {
+    fn from(e: MigrationError) -> Self {
+        match e {
+            MigrationError::TooNew { .. } => {
+                ClipboardStorageError::UnsupportedVersion(e.to_string())
+            }
+            e => ClipboardStorageError::Serialization(e.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for ClipboardStorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        ClipboardStorageError::Unknown(e.to_string())
+    }
+}
+
+impl From<JournalError> for ClipboardStorageError {
+    fn from(e: JournalError) -> Self {
+        match e {
+            JournalError::Io(e) => e.into(),
+            e => ClipboardStorageError::Serialization(e.to_string()),
+        }
+    }
+}
+
 impl ClipboardStorage {
     pub fn new(storage: File, key: Key) -> Self {
+        Self::with_backend(Box::new(JsonFile::File(storage)), key)
+    }
+
+    pub fn with_backend(storage: Box<dyn StorageBackend>, key: Key) -> Self {
         ClipboardStorage {
             storage,
             entries: vec![],
             max_entries: DEFAULT_MAX_ENTRIES,
+            eviction: EvictionPolicy::default(),
+            ttl: None,
+            sensitive: SensitivePolicy::default(),
+            clock: Arc::new(SystemClock),
             key,
+            recovery: None,
+            pending: vec![],
         }
     }
 
+    /// Persists to the file at path, which is created on the first save
+    /// Every save replaces it atomically and keeps the previous generation
+    pub fn open(path: &Path, key: Key) -> Self {
+        Self::open_format(path, key, StorageFormat::Json)
+    }
+
+    pub fn open_format(path: &Path, key: Key, format: StorageFormat) -> Self {
+        Self::with_backend(open_backend(path, format), key)
+    }
+
     /// Persists current ClipboardStorage to the Writer
+    /// A journal only appends the changes made since the last save
     pub fn save(&mut self) -> Result<(), ClipboardStorageError> {
-        let encoded: Result<Vec<EncryptedEntry>, EntryError> = self
-            .entries
-            .clone()
-            .into_iter()
-            .map(|entry| entry.encode(&self.key))
-            .collect();
-        let serialized = serde_json::to_string(&encoded.unwrap())
-            .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))?;
-
-        self.storage.set_len(0)?;
-        self.storage.seek(SeekFrom::Start(0))?;
-        self.storage.write_all(&serialized.as_bytes())?;
-        self.storage.flush()?;
-        Ok(())
+        let ops = std::mem::take(&mut self.pending);
+        let recorded = self.storage.record(&ops, &self.entries, &self.key);
+        if recorded.is_err() {
+            self.pending = ops;
+        }
+        recorded
     }
 
     /// Loads all from Reader into current ClipboardStorage
+    /// Falls back to the backup generation if the entries can't be read
+    /// Backends with an EntryIndex are only opened, their entries stay in storage
     pub fn load(&mut self) -> Result<(), ClipboardStorageError> {
-        let mut buf = String::new();
-        self.storage.read_to_string(&mut buf)?;
-        debug!("load buf: [{}]", buf);
-        if buf.is_empty() {
-            info!("initializing new empty clipboard");
-            buf = serde_json::to_string::<Vec<Entry>>(&self.entries)
-                .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))?;
-            self.save()?;
+        self.pending.clear();
+        if let Some(index) = self.storage.index() {
+            self.entries.clear();
+            index.open(&self.key)?;
+            debug!("opened clipboard history of {} entries", index.len()?);
+            return Ok(());
+        }
+        self.entries = match self.storage.read(&self.key) {
+            Ok(Some(entries)) => entries,
+            Ok(None) => {
+                info!("initializing new empty clipboard");
+                self.entries.clear();
+                self.storage.write(&self.entries, &self.key)?;
+                vec![]
+            }
+            // Written by a newer version, the backup would silently lose its changes
+            Err(e @ ClipboardStorageError::UnsupportedVersion(_)) => return Err(e),
+            Err(e) => self.restore_backup(e)?,
+        };
+        let mut assigned = false;
+        for entry in self.entries.iter_mut() {
+            assigned |= entry.assign_missing_id();
+        }
+        if assigned {
+            info!("giving clipboard entries stored without an ID one");
+            self.storage.write(&self.entries, &self.key)?;
         }
-        let decoded = serde_json::from_str::<Vec<EncryptedEntry>>(&buf)
-            .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))?;
-        self.entries = decoded
-            .into_iter()
-            .map(|encrypted| encrypted.try_into_entry(&self.key))
-            .collect::<Result<Vec<Entry>, EntryError>>()?;
 
         debug!("loaded {} clipboard entries", self.entries.len());
         Ok(())
     }
 
-    /// idx will wrap to length of entries in ClipboardStorage
-    pub fn get_entry(&self, idx: usize) -> &Entry {
-        &self.entries[idx % self.entries.len()]
+    /// Only a backup readable with the current key replaces the entries,
+    /// a wrong key leaves everything as it is
+    fn restore_backup(
+        &mut self,
+        error: ClipboardStorageError,
+    ) -> Result<Vec<Entry>, ClipboardStorageError> {
+        match self.storage.restore_backup(&error.to_string(), &self.key)? {
+            Some((entries, recovery)) => {
+                warn!(
+                    "restored {} clipboard entries from the backup",
+                    entries.len()
+                );
+                self.recovery = Some(recovery);
+                Ok(entries)
+            }
+            None => Err(error),
+        }
+    }
+
+    /// What was lost if loading fell back to the backup generation
+    pub fn recovery(&self) -> Option<&fs::Recovery> {
+        self.recovery.as_ref()
     }
 
-    pub fn list_entries(&self) -> &[Entry] {
-        &self.entries
+    /// Re-encrypts every stored entry with new_key
+    /// The stored entries are replaced in one step, on any failure
+    /// both they and the key in use are left untouched
+    pub fn rotate_key(&mut self, new_key: Key) -> Result<(), ClipboardStorageError> {
+        let entries = self.storage.read(&self.key)?.unwrap_or_default();
+        // The backup is encrypted with the key being retired
+        self.storage.replace(&entries, &new_key)?;
+        self.pending.clear();
+        self.key = new_key;
+        info!("re-encrypted {} clipboard entries", entries.len());
+        self.entries = match self.storage.index() {
+            Some(_) => vec![],
+            None => entries,
+        };
+        Ok(())
     }
 
-    pub fn size(&self) -> usize {
-        self.entries.len()
+    pub fn get_entry(&mut self, id: EntryId) -> Result<Option<Entry>, ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            return index.get(id, &self.key);
+        }
+        Ok(self.entries.iter().find(|entry| entry.id() == id).cloned())
     }
 
-    /// Clips off any entries at beginning
-    pub fn add_entry(&mut self, entry: Entry) -> Result<(), ClipboardStorageError> {
-        if let Some(idx) = self
+    /// Where the entry with id currently is
+    fn index_of(&self, id: EntryId) -> Result<usize, ClipboardStorageError> {
+        self.entries
+            .iter()
+            .position(|entry| entry.id() == id)
+            .ok_or_else(|| {
+                ClipboardStorageError::InvalidOperation(format!("No entry with id: {}", id))
+            })
+    }
+
+    /// The key the entries are encrypted with
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Every entry, most recently copied or used first
+    pub fn list_entries(&mut self) -> Result<Vec<Entry>, ClipboardStorageError> {
+        self.list(ListOrder::Recent)
+    }
+
+    pub fn size(&mut self) -> Result<usize, ClipboardStorageError> {
+        match self.storage.index() {
+            Some(index) => index.len(),
+            None => Ok(self.entries.len()),
+        }
+    }
+
+    /// Every entry in order
+    pub fn list(&mut self, order: ListOrder) -> Result<Vec<Entry>, ClipboardStorageError> {
+        let mut entries = match self.storage.index() {
+            Some(_) => self.storage.read(&self.key)?.unwrap_or_default(),
+            None => self.entries.clone(),
+        };
+        if order == ListOrder::Frecency {
+            let now = self.clock.now();
+            // Stable, equally ranked entries stay in recent order
+            entries.sort_by_key(|entry| std::cmp::Reverse(entry.frecency(now)));
+        }
+        Ok(entries)
+    }
+
+    /// Up to limit text entries matching query, best match first, see the search module
+    pub fn search(
+        &mut self,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchMatch>, ClipboardStorageError> {
+        match self.storage.index() {
+            Some(index) => index.search(query, limit, &self.key),
+            None => Ok(search::search(&self.entries, query, limit)),
+        }
+    }
+
+    /// Up to limit entries starting at offset, of those copied from selection if there is one
+    /// Backends that can are queried directly instead of using the loaded entries
+    pub fn page(
+        &mut self,
+        offset: usize,
+        limit: usize,
+        selection: Option<Selection>,
+    ) -> Result<Vec<Entry>, ClipboardStorageError> {
+        if self.pending.is_empty() {
+            if let Some(entries) = self.storage.page(offset, limit, selection, &self.key)? {
+                return Ok(entries);
+            }
+        }
+        Ok(self
             .entries
             .iter()
-            .position(|e| entry.content() == e.content())
-        {
-            self.entries.swap(0, idx);
+            .filter(|entry| selection.is_none_or(|s| entry.selection() == s))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    /// Clips off any entries at beginning
+    /// Content copied again with the same alternatives is moved to the front instead
+    pub fn add_entry(&mut self, entry: Entry) -> Result<(), ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            match index.find_copy(&entry, &self.key)? {
+                Some(id) => index.move_to_front(id, self.clock.now(), &self.key)?,
+                None => {
+                    index.add(&entry, &self.key)?;
+                    self.clip_entries_to_max_size()?;
+                }
+            }
+            return Ok(());
+        }
+        if let Some(idx) = self.entries.iter().position(|e| {
+            entry.content() == e.content() && entry.alternatives().eq(e.alternatives())
+        }) {
+            self.apply(JournalOp::MoveToFront {
+                index: idx,
+                at: self.clock.now(),
+            })?;
         } else {
-            self.entries.insert(0, entry);
-            if self.entries.len() > self.max_entries {
-                self.clip_entries_to_max_size();
+            self.apply(JournalOp::Add(entry))?;
+            self.clip_entries_to_max_size()?;
+        }
+        Ok(())
+    }
+
+    /// Changes the entries, the change is persisted by the next save
+    fn apply(&mut self, op: JournalOp) -> Result<(), ClipboardStorageError> {
+        if self.storage.records_ops() {
+            self.pending.push(op.clone());
+        }
+        op.apply(&mut self.entries)
+            .map_err(ClipboardStorageError::InvalidOperation)
+    }
+
+    /// Adds freshly copied content to the front of the ClipboardStorage and persists it
+    /// Empty content is ignored, returns whether anything was stored
+    pub fn ingest(&mut self, bytes: &[u8], kind: EntryKind) -> Result<bool, ClipboardStorageError> {
+        self.ingest_entry(Entry::new_at(bytes, kind, self.clock.now()).with_detected_sensitive())
+    }
+
+    /// Like ingest, for entries carrying more metadata than their kind
+    /// The entry is only treated as sensitive if it is marked so already
+    pub fn ingest_entry(&mut self, entry: Entry) -> Result<bool, ClipboardStorageError> {
+        if entry.content().is_empty() {
+            debug!("ignoring empty clipboard content");
+            return Ok(false);
+        }
+        let entry = match (entry.sensitive(), self.sensitive) {
+            (None, _) | (Some(_), SensitivePolicy::Keep) => entry,
+            (Some(_), SensitivePolicy::Skip) => {
+                debug!("not storing sensitive clipboard content");
+                return Ok(false);
+            }
+            (Some(_), SensitivePolicy::Expire { ttl_secs }) => {
+                entry.with_ttl(Duration::seconds(ttl_secs as i64))
             }
+        };
+        self.add_entry(entry)?;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Records that the entry with id was pasted, moving it to the front
+    pub fn use_entry(&mut self, id: EntryId) -> Result<(), ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            return index.move_to_front(id, self.clock.now(), &self.key);
         }
+        let idx = self.index_of(id)?;
+        self.apply(JournalOp::MoveToFront {
+            index: idx,
+            at: self.clock.now(),
+        })?;
+        self.save()?;
         Ok(())
     }
 
-    pub fn remove_entry(&mut self, idx: usize) -> Result<(), ClipboardStorageError> {
-        if idx >= self.entries.len() {
-            return Err(ClipboardStorageError::InvalidOperation(format!(
-                "Cannot remove entry at index: {}",
-                idx
-            )));
+    pub fn remove_entry(&mut self, id: EntryId) -> Result<(), ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            return index.remove(&[id], &self.key);
         }
-        self.entries.remove(idx);
+        let idx = self.index_of(id)?;
+        self.apply(JournalOp::Remove(idx))?;
         self.save()?;
         Ok(())
     }
 
-    fn clip_entries_to_max_size(&mut self) {
-        self.entries = self.entries.drain(0..(self.entries.len() - 1)).collect();
+    /// Limits the history to max_entries, evicting by policy from the next copy on
+    pub fn set_eviction(&mut self, max_entries: usize, policy: EvictionPolicy) {
+        self.max_entries = max_entries;
+        self.eviction = policy;
+    }
+
+    pub fn set_sensitive_policy(&mut self, policy: SensitivePolicy) {
+        self.sensitive = policy;
     }
+
+    /// Expires entries that went unused for ttl, by the next expire
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Where the time entries are created, used and expired at comes from
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Removes the expired entries, returns how many there were
+    pub fn expire(&mut self) -> Result<usize, ClipboardStorageError> {
+        let now = self.clock.now();
+        if let Some(index) = self.storage.index() {
+            let expired: Vec<EntryId> = index
+                .metadata()?
+                .into_iter()
+                .filter(|meta| meta.is_expired(now, self.ttl))
+                .map(|meta| meta.id)
+                .collect();
+            index.remove(&expired, &self.key)?;
+            if !expired.is_empty() {
+                info!("expired {} clipboard entries", expired.len());
+            }
+            return Ok(expired.len());
+        }
+        let mut expired = 0;
+        for idx in (0..self.entries.len()).rev() {
+            if self.entries[idx].is_expired(now, self.ttl) {
+                self.apply(JournalOp::Remove(idx))?;
+                expired += 1;
+            }
+        }
+        if expired > 0 {
+            info!("expired {} clipboard entries", expired);
+            self.save()?;
+        }
+        Ok(expired)
+    }
+
+    /// Keeps the entry with id through eviction and clearing
+    pub fn pin(&mut self, id: EntryId) -> Result<(), ClipboardStorageError> {
+        self.set_pinned(id, true)
+    }
+
+    /// Lets the entry with id be evicted again
+    pub fn unpin(&mut self, id: EntryId) -> Result<(), ClipboardStorageError> {
+        self.set_pinned(id, false)
+    }
+
+    fn set_pinned(&mut self, id: EntryId, pinned: bool) -> Result<(), ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            return index.set_pinned(id, pinned, &self.key);
+        }
+        let idx = self.index_of(id)?;
+        self.apply(JournalOp::Pin { index: idx, pinned })?;
+        self.save()?;
+        Ok(())
+    }
+
+    /// Removes every entry that isn't pinned
+    pub fn clear(&mut self) -> Result<(), ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            let unpinned: Vec<EntryId> = index
+                .metadata()?
+                .into_iter()
+                .filter(|meta| !meta.pinned)
+                .map(|meta| meta.id)
+                .collect();
+            return index.remove(&unpinned, &self.key);
+        }
+        for idx in (0..self.entries.len()).rev() {
+            if !self.entries[idx].is_pinned() {
+                self.apply(JournalOp::Remove(idx))?;
+            }
+        }
+        self.save()?;
+        Ok(())
+    }
+
+    /// Evicts entries until the eviction policy is satisfied
+    fn clip_entries_to_max_size(&mut self) -> Result<(), ClipboardStorageError> {
+        if let Some(index) = self.storage.index() {
+            let mut metadata = index.metadata()?;
+            let mut evicted = vec![];
+            while let Some(idx) = self.eviction.victim(&metadata, self.max_entries) {
+                evicted.push(metadata.remove(idx).id);
+            }
+            return index.remove(&evicted, &self.key);
+        }
+        while let Some(idx) = self.eviction.victim(&self.entries, self.max_entries) {
+            self.apply(JournalOp::Remove(idx))?;
+        }
+        Ok(())
+    }
+}
+
+fn serialize_entries(entries: &[Entry], key: &Key) -> Result<Vec<u8>, ClipboardStorageError> {
+    let encoded = entries
+        .iter()
+        .map(|entry| entry.encode(key))
+        .collect::<Result<Vec<EncryptedEntry>, EntryError>>()?;
+    serde_json::to_vec(&EntriesDocument {
+        version: Schema::Entries.version(),
+        entries: encoded,
+    })
+    .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))
+}
+
+/// Older versions are upgraded first
+fn parse_entries(buf: &[u8], key: &Key) -> Result<Vec<Entry>, ClipboardStorageError> {
+    let doc = serde_json::from_slice(buf)
+        .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))?;
+    let (doc, _) = migrate::upgrade(Schema::Entries, doc)?;
+    let doc = serde_json::from_value::<EntriesDocument>(doc)
+        .map_err(|e| ClipboardStorageError::Serialization(e.to_string()))?;
+    Ok(doc
+        .entries
+        .into_iter()
+        .map(|encrypted| encrypted.try_into_entry(key))
+        .collect::<Result<Vec<Entry>, EntryError>>()?)
+}
+
+/// Opens the history in dir, kept in the given format
+/// A history kept in another format is converted
+pub fn get_clipboard(
+    dir: &PathBuf,
+    key: Key,
+    format: StorageFormat,
+) -> Result<ClipboardStorage, Box<dyn Error>> {
+    let path = dir.join(format.file_name());
+    let other = StorageFormat::ALL
+        .into_iter()
+        .filter(|other| *other != format)
+        .map(|other| (other, dir.join(other.file_name())))
+        .find(|(_, other_path)| other_path.exists());
+    let (other_format, other_path) = match other {
+        Some(other) if !path.exists() => other,
+        _ => return Ok(open_clipboard(&path, key, format)?),
+    };
+
+    info!(
+        "Converting clipboard history {:?} to {:?}",
+        other_path, path
+    );
+    let mut clipboard = open_clipboard(&other_path, key, other_format)?;
+    let entries = clipboard.list_entries()?;
+    clipboard.storage = open_backend(&path, format);
+    clipboard.pending.clear();
+    clipboard.storage.write(&entries, &clipboard.key)?;
+    clipboard.entries = match clipboard.storage.index() {
+        Some(_) => vec![],
+        None => entries,
+    };
+    std::fs::remove_file(&other_path)?;
+    let _ = std::fs::remove_file(fs::backup_path(&other_path));
+    Ok(clipboard)
 }
 
-pub fn get_clipboard(dir: &PathBuf) -> Result<ClipboardStorage, Box<dyn Error>> {
-    let storage = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(dir.join("entries.json"))
-        .unwrap();
-    // TODO: setup key gracefully
-    let key: &[u8; 32] = b"Thisisakeyof32bytesThisisakeyof3";
-    let mut clipboard = ClipboardStorage::new(storage, key.to_owned());
-    clipboard.load()?;
+fn open_clipboard(
+    path: &Path,
+    key: Key,
+    format: StorageFormat,
+) -> Result<ClipboardStorage, ClipboardStorageError> {
+    let mut clipboard = ClipboardStorage::open_format(path, key, format);
+    if let Err(e) = clipboard.load() {
+        if !clipboard.reencrypt_legacy()? {
+            return Err(e);
+        }
+    }
     Ok(clipboard)
 }
 
+impl ClipboardStorage {
+    /// Histories written before key generation used a fixed key
+    /// Loads them with that key and persists them with the current one
+    fn reencrypt_legacy(&mut self) -> Result<bool, ClipboardStorageError> {
+        if &self.key == LEGACY_KEY {
+            return Ok(false);
+        }
+        let key = self.key;
+        self.key = LEGACY_KEY.to_owned();
+        if self.load().is_err() {
+            self.key = key;
+            return Ok(false);
+        }
+        info!("re-encrypting clipboard history with the configured key");
+        self.rotate_key(key)?;
+        Ok(true)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::fs::tmp_dir;
+    use crate::sensitive::SensitiveKind;
+    use chrono::Utc;
     use std::fs::{File, OpenOptions};
 
     const KEY: &[u8; 32] = b"Thisisakeyof32bytesThisisakeyof3";
@@ -221,7 +953,7 @@ mod tests {
         let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
         assert_eq!(clipboard.entries.len(), 0);
         clipboard
-            .add_entry(Entry::new(&vec![], EntryKind::Text))
+            .add_entry(Entry::new(&[], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries.len(), 1);
     }
@@ -231,10 +963,11 @@ mod tests {
         let f = new_file("");
         let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
         clipboard
-            .add_entry(Entry::new(&vec![], EntryKind::Text))
+            .add_entry(Entry::new(&[], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries.len(), 1);
-        clipboard.remove_entry(0).unwrap();
+        let id = clipboard.list_entries().unwrap()[0].id();
+        clipboard.remove_entry(id).unwrap();
         assert_eq!(clipboard.entries.len(), 0);
     }
 
@@ -244,11 +977,11 @@ mod tests {
         let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
         clipboard.max_entries = 1;
         clipboard
-            .add_entry(Entry::new(&vec![1], EntryKind::Text))
+            .add_entry(Entry::new(&[1], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries.len(), 1);
         clipboard
-            .add_entry(Entry::new(&vec![2], EntryKind::Text))
+            .add_entry(Entry::new(&[2], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries.len(), 1);
     }
@@ -259,10 +992,10 @@ mod tests {
         let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
         clipboard.max_entries = 1;
         clipboard
-            .add_entry(Entry::new(&vec![1], EntryKind::Text))
+            .add_entry(Entry::new(&[1], EntryKind::Text))
             .unwrap();
         clipboard
-            .add_entry(Entry::new(&vec![2], EntryKind::Text))
+            .add_entry(Entry::new(&[2], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries[0].content(), vec![2]);
     }
@@ -273,18 +1006,420 @@ mod tests {
         let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
         clipboard.max_entries = 2;
         clipboard
-            .add_entry(Entry::new(&vec![1], EntryKind::Text))
+            .add_entry(Entry::new(&[1], EntryKind::Text))
             .unwrap();
         clipboard
-            .add_entry(Entry::new(&vec![2], EntryKind::Text))
+            .add_entry(Entry::new(&[2], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries[0].content(), vec![2]);
         clipboard
-            .add_entry(Entry::new(&vec![3], EntryKind::Text))
+            .add_entry(Entry::new(&[3], EntryKind::Text))
             .unwrap();
         assert_eq!(clipboard.entries[0].content(), vec![3]);
     }
 
+    #[test]
+    fn test_store_moves_copied_again_entry_to_front() {
+        let f = new_file("");
+        let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
+        for bytes in [[1], [2], [3], [1]] {
+            clipboard.ingest(&bytes, EntryKind::Text).unwrap();
+        }
+        let entries = clipboard.list_entries().unwrap();
+        let contents: Vec<&[u8]> = entries.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, [[1].as_ref(), &[3], &[2]]);
+        assert_eq!(clipboard.list_entries().unwrap()[0].metadata().use_count, 1);
+    }
+
+    #[test]
+    fn test_store_keeps_copy_with_other_alternatives() {
+        let f = new_file("");
+        let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
+        let copy =
+            |html: &[u8]| Entry::new(b"link", EntryKind::Text).with_alternative("text/html", html);
+        clipboard.ingest_entry(copy(b"<a>link</a>")).unwrap();
+        clipboard.ingest_entry(copy(b"<b>link</b>")).unwrap();
+        clipboard.ingest_entry(copy(b"<a>link</a>")).unwrap();
+        let entries = clipboard.list_entries().unwrap();
+        let alternatives: Vec<_> = entries
+            .iter()
+            .flat_map(|e| e.alternatives().map(|(_, bytes)| bytes))
+            .collect();
+        assert_eq!(alternatives, [b"<a>link</a>".as_ref(), b"<b>link</b>"]);
+    }
+
+    #[test]
+    fn test_store_expires_entries() {
+        let tmp_file = temp_file::empty();
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(tmp_file.path())
+                .unwrap()
+        };
+        let mut clipboard = ClipboardStorage::new(open(), KEY.to_owned());
+        let clock = ManualClock::new(Utc::now());
+        clipboard.set_clock(Arc::new(clock.clone()));
+        clipboard.set_ttl(Some(Duration::hours(24)));
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        clipboard.ingest(&[2], EntryKind::Text).unwrap();
+        let sensitive = Entry::new_at(&[3], EntryKind::Text, clock.now());
+        clipboard
+            .ingest_entry(sensitive.with_ttl(Duration::minutes(5)))
+            .unwrap();
+        let id = clipboard.list_entries().unwrap()[2].id();
+        clipboard.pin(id).unwrap();
+
+        clock.advance(Duration::minutes(5));
+        assert_eq!(clipboard.expire().unwrap(), 1);
+        clock.advance(Duration::hours(23));
+        clipboard.ingest(&[2], EntryKind::Text).unwrap();
+        clock.advance(Duration::hours(1));
+        assert_eq!(clipboard.expire().unwrap(), 0);
+        clock.advance(Duration::hours(23));
+        assert_eq!(clipboard.expire().unwrap(), 1);
+
+        let mut reloaded = ClipboardStorage::new(open(), KEY.to_owned());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.size().unwrap(), 1);
+        assert_eq!(reloaded.list_entries().unwrap()[0].content(), vec![1]);
+    }
+
+    #[test]
+    fn test_store_applies_sensitive_policy() {
+        let password = b"x7Kq9LmZ2vRt8WpN4sYb6HjD";
+        let mut clipboard = ClipboardStorage::default();
+        let clock = ManualClock::new(Utc::now());
+        clipboard.set_clock(Arc::new(clock.clone()));
+        clipboard.ingest(password, EntryKind::Text).unwrap();
+        clipboard
+            .ingest(b"Something copied", EntryKind::Text)
+            .unwrap();
+        let entry = &clipboard.list_entries().unwrap()[1];
+        assert_eq!(entry.sensitive(), Some(SensitiveKind::HighEntropy));
+        assert_eq!(entry.content(), password);
+        assert_eq!(clipboard.list_entries().unwrap()[0].sensitive(), None);
+        clock.advance(Duration::minutes(5));
+        assert_eq!(clipboard.expire().unwrap(), 1);
+
+        clipboard.set_sensitive_policy(SensitivePolicy::Keep);
+        clipboard.ingest(password, EntryKind::Text).unwrap();
+        clock.advance(Duration::days(365));
+        assert_eq!(clipboard.expire().unwrap(), 0);
+        assert!(clipboard.list_entries().unwrap()[0].sensitive().is_some());
+
+        clipboard.set_sensitive_policy(SensitivePolicy::Skip);
+        assert!(!clipboard
+            .ingest(b"4111 1111 1111 1111", EntryKind::Text)
+            .unwrap());
+        let hinted =
+            Entry::new(b"hunter2", EntryKind::Text).with_sensitive(SensitiveKind::PasswordManager);
+        assert!(!clipboard.ingest_entry(hinted.clone()).unwrap());
+        assert_eq!(clipboard.size().unwrap(), 2);
+
+        clipboard.set_sensitive_policy(SensitivePolicy::default());
+        clipboard.ingest_entry(hinted).unwrap();
+        let entry = &clipboard.list_entries().unwrap()[0];
+        assert_eq!(entry.sensitive(), Some(SensitiveKind::PasswordManager));
+        assert!(entry.metadata().expires_at.is_some());
+        // Entries are marked by whoever made them, ingest_entry doesn't look again
+        clipboard
+            .ingest_entry(Entry::new(b"4111 1111 1111 1111", EntryKind::Text))
+            .unwrap();
+        let entry = &clipboard.list_entries().unwrap()[0];
+        assert_eq!(entry.sensitive(), None);
+        assert_eq!(entry.metadata().expires_at, None);
+    }
+
+    #[test]
+    fn test_store_lists_by_frecency() {
+        let f = new_file("");
+        let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
+        for bytes in [[1], [2], [3]] {
+            clipboard.ingest(&bytes, EntryKind::Text).unwrap();
+        }
+        let id = clipboard.list_entries().unwrap()[2].id();
+        clipboard.use_entry(id).unwrap();
+        clipboard.use_entry(id).unwrap();
+        clipboard.ingest(&[2], EntryKind::Text).unwrap();
+        clipboard.ingest(&[4], EntryKind::Text).unwrap();
+
+        let recent: Vec<Vec<u8>> = clipboard
+            .list(ListOrder::Recent)
+            .unwrap()
+            .iter()
+            .map(|e| e.content().to_vec())
+            .collect();
+        let frecent: Vec<Vec<u8>> = clipboard
+            .list(ListOrder::Frecency)
+            .unwrap()
+            .iter()
+            .map(|e| e.content().to_vec())
+            .collect();
+        assert_eq!(recent, [[4], [2], [1], [3]]);
+        assert_eq!(frecent, [[1], [2], [4], [3]]);
+    }
+
+    #[test]
+    fn test_store_evicts_by_policy() {
+        let f = new_file("");
+        let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
+        for bytes in [[1], [2], [3], [1]] {
+            clipboard.ingest(&bytes, EntryKind::Text).unwrap();
+        }
+        clipboard.set_eviction(2, EvictionPolicy::OldestFirst);
+        clipboard.ingest(&[4], EntryKind::Text).unwrap();
+        let entries = clipboard.list_entries().unwrap();
+        let contents: Vec<&[u8]> = entries.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, [[4].as_ref(), &[3]]);
+
+        clipboard.set_eviction(2, EvictionPolicy::LeastRecentlyUsed);
+        clipboard.ingest(&[3], EntryKind::Text).unwrap();
+        clipboard.ingest(&[5], EntryKind::Text).unwrap();
+        let entries = clipboard.list_entries().unwrap();
+        let contents: Vec<&[u8]> = entries.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, [[5].as_ref(), &[3]]);
+    }
+
+    #[test]
+    fn test_store_keeps_pinned_entries() {
+        let f = new_file("");
+        let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
+        clipboard.max_entries = 1;
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        let id = clipboard.list_entries().unwrap()[0].id();
+        clipboard.pin(id).unwrap();
+        clipboard.ingest(&[2], EntryKind::Text).unwrap();
+        clipboard.ingest(&[3], EntryKind::Text).unwrap();
+        assert_eq!(clipboard.size().unwrap(), 2);
+        assert_eq!(clipboard.list_entries().unwrap()[0].content(), vec![3]);
+        assert_eq!(clipboard.list_entries().unwrap()[1].content(), vec![1]);
+
+        clipboard.clear().unwrap();
+        assert_eq!(clipboard.size().unwrap(), 1);
+        assert!(clipboard.list_entries().unwrap()[0].is_pinned());
+        let id = clipboard.list_entries().unwrap()[0].id();
+        clipboard.unpin(id).unwrap();
+        clipboard.clear().unwrap();
+        assert_eq!(clipboard.size().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_store_ingest_persists_entry() {
+        let tmp_file = temp_file::empty();
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(tmp_file.path())
+                .unwrap()
+        };
+        let mut clipboard = ClipboardStorage::new(open(), KEY.to_owned());
+        assert!(clipboard.ingest(&[1, 2, 3], EntryKind::Text).unwrap());
+        assert!(clipboard.ingest(&[1, 2, 3], EntryKind::Text).unwrap());
+        assert!(!clipboard.ingest(&[], EntryKind::Text).unwrap());
+
+        let mut reloaded = ClipboardStorage::new(open(), KEY.to_owned());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.size().unwrap(), 1);
+        assert_eq!(reloaded.list_entries().unwrap()[0].content(), vec![1, 2, 3]);
+        assert_eq!(
+            reloaded.list_entries().unwrap()[0],
+            clipboard.list_entries().unwrap()[0]
+        );
+    }
+
+    #[test]
+    fn test_store_copying_again_updates_last_used() {
+        let f = new_file("");
+        let mut clipboard = ClipboardStorage::new(f, KEY.to_owned());
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        clipboard.ingest(&[2], EntryKind::Text).unwrap();
+        let created = clipboard.list_entries().unwrap()[1].metadata().clone();
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        let meta = clipboard.list_entries().unwrap()[0].metadata().clone();
+        assert_eq!(meta.created_at, created.created_at);
+        assert!(meta.last_used_at >= created.last_used_at);
+    }
+
+    #[test]
+    fn test_store_reencrypts_legacy_history() {
+        let dir = tmp_dir();
+        let entry = Entry::new(&[1, 2, 3], EntryKind::Text);
+        let json_s = serde_json::to_string(&vec![entry.encode(LEGACY_KEY).unwrap()]).unwrap();
+        std::fs::write(dir.join("entries.json"), json_s).unwrap();
+
+        let key = generate_encryption_key();
+        let mut clipboard = get_clipboard(&dir, key, StorageFormat::Json).unwrap();
+        assert_eq!(clipboard.size().unwrap(), 1);
+        let reloaded = get_clipboard(&dir, key, StorageFormat::Json);
+        let wrong_key = get_clipboard(&dir, generate_encryption_key(), StorageFormat::Json);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            reloaded.unwrap().list_entries().unwrap()[0].content(),
+            vec![1, 2, 3]
+        );
+        assert!(wrong_key.is_err());
+    }
+
+    #[test]
+    fn test_store_gives_stored_entries_stable_ids() {
+        let dir = tmp_dir();
+        let path = dir.join(ENTRIES_FILE_NAME);
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/entries_v0_without_metadata.json");
+        std::fs::copy(fixture, &path).unwrap();
+        let mut clipboard = ClipboardStorage::open(&path, KEY.to_owned());
+        clipboard.load().unwrap();
+        let mut reloaded = ClipboardStorage::open(&path, KEY.to_owned());
+        reloaded.load().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let ids: Vec<EntryId> = clipboard
+            .list_entries()
+            .unwrap()
+            .iter()
+            .map(Entry::id)
+            .collect();
+        assert!(ids.iter().all(|id| !id.is_nil()));
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(
+            reloaded.list_entries().unwrap(),
+            clipboard.list_entries().unwrap()
+        );
+        assert_eq!(
+            reloaded.get_entry(ids[1]).unwrap().unwrap().content(),
+            b"first"
+        );
+    }
+
+    #[test]
+    fn test_store_rotate_key_reencrypts_history() {
+        let dir = tmp_dir();
+        let path = dir.join("entries.json");
+        let mut clipboard = ClipboardStorage::open(&path, KEY.to_owned());
+        clipboard.ingest(&[1, 2, 3], EntryKind::Text).unwrap();
+        let new_key = generate_encryption_key();
+        clipboard.rotate_key(new_key).unwrap();
+        assert_eq!(clipboard.key(), &new_key);
+        clipboard.ingest(&[4, 5], EntryKind::Text).unwrap();
+
+        let mut old = ClipboardStorage::open(&path, KEY.to_owned());
+        let old_load = old.load();
+        let mut reloaded = ClipboardStorage::open(&path, new_key);
+        reloaded.load().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(old_load.is_err());
+        assert!(reloaded.recovery().is_none());
+        assert_eq!(reloaded.size().unwrap(), 2);
+        assert_eq!(reloaded.list_entries().unwrap()[1].content(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_store_rotate_key_keeps_history_on_failure() {
+        let dir = tmp_dir();
+        let path = dir.join("entries.json");
+        let mut clipboard = ClipboardStorage::open(&path, KEY.to_owned());
+        clipboard.ingest(&[1, 2, 3], EntryKind::Text).unwrap();
+        let before = std::fs::read(&path).unwrap();
+        // Entries this key can't decrypt make the rotation fail before anything is written
+        clipboard.key = generate_encryption_key();
+        let wrong_key = clipboard.key;
+        assert!(clipboard.rotate_key(generate_encryption_key()).is_err());
+        let after = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(clipboard.key(), &wrong_key);
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_store_restores_backup_of_corrupt_history() {
+        let dir = tmp_dir();
+        let path = dir.join("entries.json");
+        let mut clipboard = ClipboardStorage::open(&path, KEY.to_owned());
+        clipboard.ingest(&[1, 2, 3], EntryKind::Text).unwrap();
+        clipboard.ingest(&[4, 5], EntryKind::Text).unwrap();
+        // A torn write from before saves were atomic
+        let written = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &written[..written.len() / 2]).unwrap();
+
+        let mut reloaded = ClipboardStorage::open(&path, KEY.to_owned());
+        reloaded.load().unwrap();
+        let recovery = reloaded.recovery().cloned().unwrap();
+        let corrupt = std::fs::read(&recovery.corrupt_path).unwrap();
+        let mut wrong_key = ClipboardStorage::open(&path, generate_encryption_key());
+        let wrong_key_load = wrong_key.load();
+        let current = std::fs::read(&path).unwrap();
+        let backup = std::fs::read(fs::backup_path(&path)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reloaded.size().unwrap(), 1);
+        assert_eq!(reloaded.list_entries().unwrap()[0].content(), vec![1, 2, 3]);
+        assert_eq!(corrupt.len(), written.len() / 2);
+        assert!(wrong_key_load.is_err());
+        assert_eq!(current, backup);
+    }
+
+    #[test]
+    fn test_store_journal_keeps_changes() {
+        let dir = tmp_dir();
+        let path = dir.join(JOURNAL_FILE_NAME);
+        let mut clipboard =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Journal);
+        clipboard.load().unwrap();
+        clipboard.max_entries = 2;
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        clipboard.ingest(&[2], EntryKind::Text).unwrap();
+        clipboard.ingest(&[1], EntryKind::Text).unwrap();
+        clipboard.ingest(&[3], EntryKind::Text).unwrap();
+        let id = clipboard.list_entries().unwrap()[0].id();
+        clipboard.remove_entry(id).unwrap();
+        let id = clipboard.list_entries().unwrap()[0].id();
+        clipboard.pin(id).unwrap();
+
+        let mut reloaded =
+            ClipboardStorage::open_format(&path, KEY.to_owned(), StorageFormat::Journal);
+        reloaded.load().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            reloaded.list_entries().unwrap(),
+            clipboard.list_entries().unwrap()
+        );
+        assert_eq!(reloaded.list_entries().unwrap()[0].content(), vec![1]);
+        assert!(reloaded.list_entries().unwrap()[0].is_pinned());
+        assert_eq!(reloaded.size().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_store_converts_between_formats() {
+        let dir = tmp_dir();
+        let key = generate_encryption_key();
+        let mut clipboard = get_clipboard(&dir, key, StorageFormat::Json).unwrap();
+        clipboard.ingest(&[1, 2, 3], EntryKind::Text).unwrap();
+
+        let mut journal = get_clipboard(&dir, key, StorageFormat::Journal).unwrap();
+        let json_exists = dir.join(ENTRIES_FILE_NAME).exists();
+        let mut sqlite = get_clipboard(&dir, key, StorageFormat::Sqlite).unwrap();
+        let journal_exists = dir.join(JOURNAL_FILE_NAME).exists();
+        let mut json = get_clipboard(&dir, key, StorageFormat::Json).unwrap();
+        let sqlite_exists = dir.join(SQLITE_FILE_NAME).exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            journal.list_entries().unwrap(),
+            clipboard.list_entries().unwrap()
+        );
+        assert_eq!(
+            sqlite.list_entries().unwrap(),
+            clipboard.list_entries().unwrap()
+        );
+        assert_eq!(
+            json.list_entries().unwrap(),
+            clipboard.list_entries().unwrap()
+        );
+        assert!(!json_exists);
+        assert!(!journal_exists);
+        assert!(!sqlite_exists);
+    }
+
     #[test]
     fn test_store_load_works() {
         let bytes = vec![1, 2, 3, 4];
@@ -1,3 +1,5 @@
 mod dialog;
+mod unlock;
 
 pub use dialog::{Dialog, DialogMsg};
+pub use unlock::{UnlockDialog, UnlockMsg, UnlockOutput};
@@ -0,0 +1,77 @@
+use gtk::prelude::{EditableExt, GtkWindowExt, OrientableExt, WidgetExt};
+use relm4::{gtk, RelmWidgetExt};
+use relm4::{ComponentParts, ComponentSender, SimpleComponent};
+
+/// Asks for the passphrase of a locked history
+pub struct UnlockDialog {
+    visible: bool,
+}
+
+#[derive(Debug)]
+pub enum UnlockMsg {
+    Show,
+    Hide,
+}
+
+#[derive(Debug)]
+pub enum UnlockOutput {
+    Passphrase(String),
+}
+
+#[relm4::component(pub)]
+impl SimpleComponent for UnlockDialog {
+    type Init = ();
+    type Input = UnlockMsg;
+    type Output = UnlockOutput;
+
+    view! {
+        dialog = gtk::Dialog {
+            #[watch]
+            set_visible: model.visible,
+            set_modal: true,
+            set_title: Some("Unlock clipboard history"),
+
+            #[wrap(Some)]
+            set_child = &gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+                set_margin_all: 12,
+                set_spacing: 6,
+
+                gtk::Label {
+                    set_label: "Enter the passphrase of your clipboard history",
+                },
+
+                gtk::PasswordEntry {
+                    set_show_peek_icon: true,
+
+                    connect_activate[sender] => move |entry| {
+                        sender.output(UnlockOutput::Passphrase(entry.text().to_string()));
+                        entry.set_text("");
+                    }
+                },
+            },
+
+            connect_close_request[sender] => move |_| {
+                sender.input(UnlockMsg::Hide);
+                gtk::Inhibit(false)
+            }
+        }
+    }
+
+    fn init(
+        _init: Self::Init,
+        root: &Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = UnlockDialog { visible: false };
+        let widgets = view_output!();
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
+        match msg {
+            UnlockMsg::Show => self.visible = true,
+            UnlockMsg::Hide => self.visible = false,
+        }
+    }
+}
@@ -1,6 +1,11 @@
-use crate::components::{Dialog, DialogMsg};
+use crate::components::{Dialog, DialogMsg, UnlockDialog, UnlockMsg, UnlockOutput};
 
-use fast_clipboard::entry::Entry;
+use fast_clipboard::entry::{Entry, EntryId};
+use fast_clipboard::fs::Recovery;
+use fast_clipboard::images::{ListedEntry, Thumbnail, THUMBNAIL_SIZE};
+use fast_clipboard::search::SearchMatch;
+
+use std::sync::Arc;
 
 use jsonrpsee::{
     core::client::{Client, ClientT},
@@ -10,8 +15,8 @@ use jsonrpsee::{
 use relm4::{
     factory::FactoryVecDeque,
     gtk::{
-        self,
-        glib::{clone, MainContext},
+        self, gdk,
+        glib::{self, clone, MainContext},
         prelude::*,
     },
     prelude::*,
@@ -21,27 +26,34 @@ use relm4::{
 use log::info;
 
 static DIALOG_BROKER: MessageBroker<Dialog> = MessageBroker::new();
+static UNLOCK_BROKER: MessageBroker<UnlockDialog> = MessageBroker::new();
 
 const APPLICATION_ID: &str = "com.github.aburd.fast-clipboard-manager";
 
 #[derive(Debug)]
 struct Task {
+    /// Image entries come without their content, the daemon selects them by ID
     entry: Entry,
+    /// Preview of image entries
+    thumbnail: Option<gdk::MemoryTexture>,
 }
 
 #[derive(Debug)]
 enum TaskInput {
     Selected,
+    TogglePin,
+    Delete,
 }
 
 #[derive(Debug)]
 enum TaskOutput {
-    Delete(DynamicIndex),
+    Delete(EntryId),
+    SetPinned(EntryId, bool),
 }
 
 #[relm4::factory]
 impl FactoryComponent for Task {
-    type Init = Entry;
+    type Init = ListedEntry;
     type Input = TaskInput;
     type Output = TaskOutput;
     type CommandOutput = ();
@@ -52,20 +64,39 @@ impl FactoryComponent for Task {
         gtk::Box {
             set_orientation: gtk::Orientation::Horizontal,
 
+            gtk::Image {
+                set_visible: self.thumbnail.is_some(),
+                set_paintable: self.thumbnail.as_ref(),
+                set_pixel_size: THUMBNAIL_SIZE as i32,
+                set_margin_start: 12,
+            },
+
             #[name(label)]
             gtk::Label {
                 set_label: &self.entry.to_string(),
+                // Sensitive entries are masked in their label
+                set_tooltip_text: self.entry.sensitive().map(|_| "Hidden, it looks like a secret"),
                 set_hexpand: true,
                 set_halign: gtk::Align::Start,
                 set_margin_all: 12,
             },
 
+            gtk::Button {
+                set_icon_name: if self.entry.is_pinned() { "starred" } else { "non-starred" },
+                set_tooltip_text: Some(if self.entry.is_pinned() { "Unpin" } else { "Pin" }),
+                set_margin_all: 12,
+
+                connect_clicked[sender] => move |_| {
+                    sender.input(TaskInput::TogglePin);
+                }
+            },
+
             gtk::Button {
                 set_icon_name: "edit-delete",
                 set_margin_all: 12,
 
-                connect_clicked[sender, index] => move |_| {
-                    sender.output(TaskOutput::Delete(index.clone()));
+                connect_clicked[sender] => move |_| {
+                    sender.input(TaskInput::Delete);
                 }
             }
         }
@@ -79,13 +110,40 @@ impl FactoryComponent for Task {
 
     fn output_to_parent_input(output: Self::Output) -> Option<AppMsg> {
         Some(match output {
-            TaskOutput::Delete(index) => AppMsg::DeleteEntry(index),
+            TaskOutput::Delete(id) => AppMsg::DeleteEntry(id),
+            TaskOutput::SetPinned(id, pinned) => AppMsg::SetPinned(id, pinned),
         })
     }
 
-    fn init_model(entry: Self::Init, _index: &DynamicIndex, _sender: FactorySender<Self>) -> Self {
-        Self { entry }
+    fn update(&mut self, msg: Self::Input, sender: FactorySender<Self>) {
+        match msg {
+            TaskInput::Selected => {}
+            TaskInput::TogglePin => sender.output(TaskOutput::SetPinned(
+                self.entry.id(),
+                !self.entry.is_pinned(),
+            )),
+            TaskInput::Delete => sender.output(TaskOutput::Delete(self.entry.id())),
+        }
     }
+
+    fn init_model(listed: Self::Init, _index: &DynamicIndex, _sender: FactorySender<Self>) -> Self {
+        Self {
+            entry: listed.entry,
+            thumbnail: listed.thumbnail.map(texture),
+        }
+    }
+}
+
+/// A texture to preview an image entry with, made by the daemon so nothing is decoded here
+fn texture(thumbnail: Thumbnail) -> gdk::MemoryTexture {
+    let dimensions = thumbnail.dimensions;
+    gdk::MemoryTexture::new(
+        dimensions.width as i32,
+        dimensions.height as i32,
+        gdk::MemoryFormat::R8g8b8a8,
+        &glib::Bytes::from_owned(thumbnail.rgba),
+        dimensions.width as usize * 4,
+    )
 }
 
 struct AppInit {
@@ -95,19 +153,47 @@ struct AppInit {
 #[derive(Debug)]
 pub enum AppErr {
     CantConnectToDaemon(String),
+    Daemon(String),
 }
 
 #[derive(Debug)]
 enum AppMsg {
-    DeleteEntry(DynamicIndex),
-    AddEntry(Entry),
+    DeleteEntry(EntryId),
+    /// Replaces the listed entries with the ones loaded by the numbered load
+    SetEntries {
+        load: u64,
+        entries: Vec<ListedEntry>,
+    },
+    SetPinned(EntryId, bool),
+    /// A row of the pinned or the other entries was clicked or entered
+    Activated {
+        pinned: bool,
+        index: usize,
+    },
+    /// Puts the entry on the clipboard and closes the window
+    SelectEntry(EntryId),
+    LoadEntries,
+    /// Lists only the entries matching the query, all of them if it is empty
+    Search(String),
+    /// Tells about entries lost when the history was restored from its backup
+    CheckRecovery,
+    Locked,
+    Unlock(String),
     Error(AppErr),
     Noop,
 }
 
 struct App {
+    /// Pinned entries, listed above the others
+    pinned: FactoryVecDeque<Task>,
     tasks: FactoryVecDeque<Task>,
     dialog: Controller<Dialog>,
+    unlock: Controller<UnlockDialog>,
+    client: Option<Arc<Client>>,
+    /// What the listed entries are filtered by
+    query: String,
+    /// Number of the latest load of the entries, earlier ones answering late are dropped
+    load: u64,
 }
 
 #[relm4::component]
@@ -128,11 +214,10 @@ impl SimpleComponent for App {
                 set_margin_all: 12,
                 set_spacing: 6,
 
-                gtk::Entry {
-                    connect_activate[sender] => move |entry| {
-                        // let buffer = entry.buffer();
-                        // sender.input(AppMsg::AddEntry(buffer.text()));
-                        // buffer.delete_text(0, None);
+                gtk::SearchEntry {
+                    set_placeholder_text: Some("Search"),
+                    connect_search_changed[sender] => move |entry| {
+                        sender.input(AppMsg::Search(entry.text().to_string()));
                     }
                 },
 
@@ -141,21 +226,187 @@ impl SimpleComponent for App {
                     set_min_content_height: 360,
                     set_vexpand: true,
 
-                    #[local_ref]
-                    task_list_box -> gtk::ListBox {}
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Vertical,
+                        set_spacing: 6,
+
+                        gtk::Label {
+                            set_label: "Pinned",
+                            set_halign: gtk::Align::Start,
+                            #[watch]
+                            set_visible: !model.pinned.is_empty(),
+                        },
+
+                        #[local_ref]
+                        pinned_list_box -> gtk::ListBox {
+                            #[watch]
+                            set_visible: !model.pinned.is_empty(),
+                            connect_row_activated[sender] => move |_, row| {
+                                let index = row.index() as usize;
+                                sender.input(AppMsg::Activated { pinned: true, index });
+                            }
+                        },
+
+                        #[local_ref]
+                        task_list_box -> gtk::ListBox {
+                            connect_row_activated[sender] => move |_, row| {
+                                let index = row.index() as usize;
+                                sender.input(AppMsg::Activated { pinned: false, index });
+                            }
+                        }
+                    }
                 }
             }
 
         }
     }
 
-    fn update(&mut self, msg: AppMsg, _sender: ComponentSender<Self>) {
+    fn update(&mut self, msg: AppMsg, sender: ComponentSender<Self>) {
         match msg {
-            AppMsg::DeleteEntry(index) => {
-                self.tasks.guard().remove(index.current_index());
+            AppMsg::DeleteEntry(id) => {
+                let Some(client) = self.client.clone() else {
+                    return;
+                };
+                MainContext::default().spawn(async move {
+                    match client
+                        .request::<bool, _>("remove_entry", rpc_params!(id))
+                        .await
+                    {
+                        Ok(_) => sender.input(AppMsg::LoadEntries),
+                        Err(e) => sender.input(AppMsg::Error(AppErr::Daemon(e.to_string()))),
+                    }
+                });
+            }
+            AppMsg::SetEntries { load, entries } => {
+                if load != self.load {
+                    return;
+                }
+                let mut pinned = self.pinned.guard();
+                let mut tasks = self.tasks.guard();
+                pinned.clear();
+                tasks.clear();
+                for entry in entries {
+                    if entry.entry.is_pinned() {
+                        pinned.push_back(entry);
+                    } else {
+                        tasks.push_back(entry);
+                    }
+                }
             }
-            AppMsg::AddEntry(entry) => {
-                self.tasks.guard().push_back(entry);
+            AppMsg::SetPinned(id, pinned) => {
+                let Some(client) = self.client.clone() else {
+                    return;
+                };
+                let method = if pinned { "pin_entry" } else { "unpin_entry" };
+                MainContext::default().spawn(async move {
+                    match client.request::<bool, _>(method, rpc_params!(id)).await {
+                        Ok(_) => sender.input(AppMsg::LoadEntries),
+                        Err(e) => sender.input(AppMsg::Error(AppErr::Daemon(e.to_string()))),
+                    }
+                });
+            }
+            AppMsg::Activated { pinned, index } => {
+                let list = if pinned { &self.pinned } else { &self.tasks };
+                if let Some(task) = list.get(index) {
+                    sender.input(AppMsg::SelectEntry(task.entry.id()));
+                }
+            }
+            AppMsg::SelectEntry(id) => {
+                let Some(client) = self.client.clone() else {
+                    return;
+                };
+                MainContext::default().spawn(async move {
+                    match client
+                        .request::<bool, _>("select_entry", rpc_params!(id))
+                        .await
+                    {
+                        Ok(_) => relm4::main_application().quit(),
+                        Err(e) => sender.input(AppMsg::Error(AppErr::Daemon(e.to_string()))),
+                    }
+                });
+            }
+            AppMsg::LoadEntries => {
+                let Some(client) = self.client.clone() else {
+                    return;
+                };
+                self.load += 1;
+                let load = self.load;
+                let query = self.query.clone();
+                MainContext::default().spawn(async move {
+                    // A daemon of another version can answer with something that doesn't parse
+                    let entries: Result<Vec<ListedEntry>, String> = if query.is_empty() {
+                        client
+                            .request::<String, _>("get_entries", rpc_params!())
+                            .await
+                            .map_err(|e| e.to_string())
+                            .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+                    } else {
+                        client
+                            .request::<String, _>("search_entries", rpc_params!(query))
+                            .await
+                            .map_err(|e| e.to_string())
+                            .and_then(|s| {
+                                let matches: Vec<SearchMatch> =
+                                    serde_json::from_str(&s).map_err(|e| e.to_string())?;
+                                // Only text entries match, they are listed as they are
+                                Ok(matches
+                                    .into_iter()
+                                    .map(|m| ListedEntry::new(&m.entry, None))
+                                    .collect())
+                            })
+                    };
+                    match entries {
+                        Ok(entries) => sender.input(AppMsg::SetEntries { load, entries }),
+                        Err(e) => sender.input(AppMsg::Error(AppErr::Daemon(e))),
+                    }
+                });
+            }
+            AppMsg::Search(query) => {
+                self.query = query;
+                sender.input(AppMsg::LoadEntries);
+            }
+            AppMsg::CheckRecovery => {
+                let Some(client) = self.client.clone() else {
+                    return;
+                };
+                MainContext::default().spawn(async move {
+                    let recovery = client
+                        .request::<String, _>("get_recovery", rpc_params!())
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|s| {
+                            serde_json::from_str::<Option<Recovery>>(&s).map_err(|e| e.to_string())
+                        });
+                    match recovery {
+                        Ok(Some(recovery)) => {
+                            let msg = format!("The history could not be read. {}", recovery);
+                            DIALOG_BROKER.send(DialogMsg::Show(msg));
+                        }
+                        Ok(None) => {}
+                        Err(e) => sender.input(AppMsg::Error(AppErr::Daemon(e))),
+                    }
+                });
+            }
+            AppMsg::Locked => {
+                UNLOCK_BROKER.send(UnlockMsg::Show);
+            }
+            AppMsg::Unlock(passphrase) => {
+                let Some(client) = self.client.clone() else {
+                    return;
+                };
+                MainContext::default().spawn(async move {
+                    match client
+                        .request::<bool, _>("unlock", rpc_params!(passphrase))
+                        .await
+                    {
+                        Ok(_) => {
+                            UNLOCK_BROKER.send(UnlockMsg::Hide);
+                            sender.input(AppMsg::LoadEntries);
+                            sender.input(AppMsg::CheckRecovery);
+                        }
+                        Err(e) => sender.input(AppMsg::Error(AppErr::Daemon(e.to_string()))),
+                    }
+                });
             }
             AppMsg::Error(e) => {
                 let msg = format!("{:?}", e);
@@ -173,28 +424,45 @@ impl SimpleComponent for App {
             .transient_for(root)
             .launch_with_broker((), &DIALOG_BROKER)
             .forward(sender.input_sender(), |()| AppMsg::Noop);
+        let unlock = UnlockDialog::builder()
+            .transient_for(root)
+            .launch_with_broker((), &UNLOCK_BROKER)
+            .forward(sender.input_sender(), |output| match output {
+                UnlockOutput::Passphrase(passphrase) => AppMsg::Unlock(passphrase),
+            });
+        let (client, error) = match app_init.client {
+            Ok(client) => (Some(Arc::new(client)), None),
+            Err(e) => (None, Some(e)),
+        };
         let model = App {
             dialog,
+            unlock,
+            client,
+            query: String::new(),
+            load: 0,
+            pinned: FactoryVecDeque::new(gtk::ListBox::default(), sender.input_sender()),
             tasks: FactoryVecDeque::new(gtk::ListBox::default(), sender.input_sender()),
         };
+        let pinned_list_box = model.pinned.widget();
         let task_list_box = model.tasks.widget();
         let widgets = view_output!();
 
-        match app_init.client {
-            Ok(client) => {
-                let sender_clone = sender.clone();
-                let main_context = MainContext::default();
-                main_context.spawn(async move {
-                    let s: String = client.request("get_entries", rpc_params!()).await.unwrap();
-                    let entries: Vec<Entry> = serde_json::from_str(&s).unwrap();
-                    for entry in entries {
-                        sender_clone.input(AppMsg::AddEntry(entry));
+        if let Some(client) = model.client.clone() {
+            let sender_clone = sender.clone();
+            let main_context = MainContext::default();
+            main_context.spawn(async move {
+                match client.request::<bool, _>("is_locked", rpc_params!()).await {
+                    Ok(true) => sender_clone.input(AppMsg::Locked),
+                    Ok(false) => {
+                        sender_clone.input(AppMsg::LoadEntries);
+                        sender_clone.input(AppMsg::CheckRecovery);
                     }
-                });
-            }
-            Err(e) => {
-                sender.input(AppMsg::Error(e));
-            }
+                    Err(e) => sender_clone.input(AppMsg::Error(AppErr::Daemon(e.to_string()))),
+                }
+            });
+        }
+        if let Some(e) = error {
+            sender.input(AppMsg::Error(e));
         }
 
         ComponentParts { model, widgets }
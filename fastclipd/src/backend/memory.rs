@@ -0,0 +1,68 @@
+use super::{BackendError, ClipboardBackend, MimeContent, SelectionChanged};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// A clipboard living only in memory
+/// Lets tests and CI drive the daemon without a display server
+#[derive(Default)]
+pub struct MemoryBackend {
+    selection: Mutex<Vec<MimeContent>>,
+    watchers: Mutex<Vec<UnboundedSender<SelectionChanged>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    /// Simulates another application copying the given contents
+    pub fn copy(&self, contents: Vec<MimeContent>) {
+        *self.selection.lock().unwrap() = contents;
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(SelectionChanged).is_ok());
+    }
+
+    /// Simulates another application copying plain text
+    #[cfg(test)]
+    pub fn copy_text(&self, text: &str) {
+        self.copy(vec![MimeContent::new(
+            "text/plain;charset=utf-8",
+            text.as_bytes(),
+        )]);
+    }
+}
+
+impl ClipboardBackend for MemoryBackend {
+    fn offers(&self) -> Result<Vec<String>, BackendError> {
+        let selection = self.selection.lock().unwrap();
+        if selection.is_empty() {
+            return Err(BackendError::Empty);
+        }
+        Ok(selection.iter().map(|c| c.mime.clone()).collect())
+    }
+
+    fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+        let selection = self.selection.lock().unwrap();
+        if selection.is_empty() {
+            return Err(BackendError::Empty);
+        }
+        selection
+            .iter()
+            .find(|c| c.mime == mime)
+            .map(|c| c.bytes.clone())
+            .ok_or_else(|| BackendError::MimeNotOffered(mime.to_string()))
+    }
+
+    fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+        self.copy(contents);
+        Ok(())
+    }
+
+    fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.watchers.lock().unwrap().push(tx);
+        Ok(rx)
+    }
+}
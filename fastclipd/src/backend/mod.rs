@@ -0,0 +1,90 @@
+/// Access to the system clipboard, independent of the display server
+mod memory;
+mod wayland;
+mod x11;
+
+pub use memory::MemoryBackend;
+pub use wayland::WaylandBackend;
+pub use x11::X11Backend;
+
+use fast_clipboard::entry::Selection;
+use log::info;
+use std::{env, sync::Arc};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Environment variable to force a backend: wayland, x11 or memory
+const BACKEND_ENV: &str = "FASTCLIPD_BACKEND";
+
+#[derive(Error, Debug)]
+pub enum BackendError {
+    #[error("the clipboard is empty")]
+    Empty,
+    #[error("mime type is not offered: {0}")]
+    MimeNotOffered(String),
+    #[error("clipboard backend unavailable: {0}")]
+    Unavailable(String),
+    #[error("error reading the clipboard: {0}")]
+    Read(String),
+    #[error("error setting the clipboard: {0}")]
+    Write(String),
+}
+
+/// Notification that the selection has changed
+/// Carries no content, the content has to be read from the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionChanged;
+
+/// Content of a selection for one mime type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeContent {
+    pub mime: String,
+    pub bytes: Vec<u8>,
+}
+
+impl MimeContent {
+    pub fn new(mime: &str, bytes: &[u8]) -> Self {
+        MimeContent {
+            mime: mime.to_string(),
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+/// A clipboard the daemon can read from, write to and watch
+/// All calls may block and should be kept off the async runtime
+pub trait ClipboardBackend: Send + Sync {
+    /// Mime types offered by the current selection
+    fn offers(&self) -> Result<Vec<String>, BackendError>;
+    /// Content of the current selection for one of its offered mime types
+    fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError>;
+    /// Makes the given contents the current selection
+    fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError>;
+    /// Starts watching the selection, a notification is sent on every change
+    fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError>;
+}
+
+/// Picks the backend for the selection of the running session
+/// Can be overridden with the FASTCLIPD_BACKEND environment variable
+pub fn detect(selection: Selection) -> Result<Arc<dyn ClipboardBackend>, BackendError> {
+    let name = match env::var(BACKEND_ENV) {
+        Ok(name) => name,
+        Err(_) if env::var_os("WAYLAND_DISPLAY").is_some() => "wayland".to_string(),
+        Err(_) if env::var_os("DISPLAY").is_some() => "x11".to_string(),
+        Err(_) => {
+            return Err(BackendError::Unavailable(
+                "neither WAYLAND_DISPLAY nor DISPLAY is set".to_string(),
+            ))
+        }
+    };
+    info!("using {} backend for the {:?} selection", name, selection);
+    match name.as_str() {
+        "wayland" => Ok(Arc::new(WaylandBackend::new(selection))),
+        "x11" => Ok(Arc::new(X11Backend::new(selection)?)),
+        "memory" => Ok(Arc::new(MemoryBackend::new())),
+        other => Err(BackendError::Unavailable(format!(
+            "unknown backend in {}: {}",
+            BACKEND_ENV, other
+        ))),
+    }
+}
@@ -0,0 +1,84 @@
+mod watcher;
+
+use super::{BackendError, ClipboardBackend, MimeContent, SelectionChanged};
+use fast_clipboard::entry::Selection;
+use std::io::Read;
+use tokio::sync::mpsc::UnboundedReceiver;
+use wl_clipboard_rs::{
+    copy::{self, MimeSource, Source},
+    paste::{self, get_contents, get_mime_types, ClipboardType, Seat},
+};
+
+/// A Wayland selection, through the wlr data-control protocol
+pub struct WaylandBackend {
+    selection: Selection,
+}
+
+impl WaylandBackend {
+    pub fn new(selection: Selection) -> Self {
+        WaylandBackend { selection }
+    }
+
+    fn clipboard_type(&self) -> ClipboardType {
+        match self.selection {
+            Selection::Clipboard => ClipboardType::Regular,
+            Selection::Primary => ClipboardType::Primary,
+        }
+    }
+}
+
+fn paste_error(e: paste::Error) -> BackendError {
+    match e {
+        paste::Error::NoSeats | paste::Error::ClipboardEmpty | paste::Error::NoMimeType => {
+            BackendError::Empty
+        }
+        e => BackendError::Read(e.to_string()),
+    }
+}
+
+impl ClipboardBackend for WaylandBackend {
+    fn offers(&self) -> Result<Vec<String>, BackendError> {
+        let mut offers: Vec<String> = get_mime_types(self.clipboard_type(), Seat::Unspecified)
+            .map_err(paste_error)?
+            .into_iter()
+            .collect();
+        offers.sort();
+        Ok(offers)
+    }
+
+    fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+        let (mut pipe, _) = get_contents(
+            self.clipboard_type(),
+            Seat::Unspecified,
+            paste::MimeType::Specific(mime),
+        )
+        .map_err(paste_error)?;
+        let mut contents = vec![];
+        pipe.read_to_end(&mut contents)
+            .map_err(|e| BackendError::Read(e.to_string()))?;
+        Ok(contents)
+    }
+
+    fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+        let sources = contents
+            .into_iter()
+            .map(|c| MimeSource {
+                source: Source::Bytes(c.bytes.into_boxed_slice()),
+                mime_type: copy::MimeType::Specific(c.mime),
+            })
+            .collect();
+        let mut options = copy::Options::new();
+        options.clipboard(match self.selection {
+            Selection::Clipboard => copy::ClipboardType::Regular,
+            Selection::Primary => copy::ClipboardType::Primary,
+        });
+        // Requests are served from a background thread until something else is copied
+        options
+            .copy_multi(sources)
+            .map_err(|e| BackendError::Write(e.to_string()))
+    }
+
+    fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+        watcher::watch(self.selection).map_err(|e| BackendError::Unavailable(e.to_string()))
+    }
+}
@@ -0,0 +1,141 @@
+/// Watches the Wayland selection through the wlr data-control protocol
+use crate::backend::SelectionChanged;
+use fast_clipboard::entry::Selection;
+use log::{debug, error, info};
+use std::thread;
+use thiserror::Error;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use wayland_client::{
+    protocol::wl_seat::WlSeat, ConnectError, Display, GlobalError, GlobalManager,
+};
+use wayland_protocols::wlr::unstable::data_control::v1::client::{
+    zwlr_data_control_device_v1, zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+};
+
+#[derive(Error, Debug)]
+pub enum WatcherError {
+    #[error("could not connect to the wayland compositor: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("compositor is missing a required global: {0}")]
+    MissingGlobal(#[from] GlobalError),
+    #[error("error talking to the wayland compositor: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Starts watching the selection on a dedicated thread
+/// Fails early if the compositor does not support data-control,
+/// or for the primary selection the version of it that has one
+pub fn watch(selection: Selection) -> Result<UnboundedReceiver<SelectionChanged>, WatcherError> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+
+    thread::Builder::new()
+        .name("selection-watcher".to_string())
+        .spawn(move || {
+            if let Err(e) = run(selection, tx, ready_tx) {
+                error!("selection watcher stopped: {}", e);
+            }
+        })?;
+
+    // The thread always reports whether it managed to bind the globals
+    match ready_rx.recv() {
+        Ok(Ok(())) => Ok(rx),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Ok(rx),
+    }
+}
+
+fn run(
+    selection: Selection,
+    tx: UnboundedSender<SelectionChanged>,
+    ready_tx: std::sync::mpsc::SyncSender<Result<(), WatcherError>>,
+) -> Result<(), WatcherError> {
+    let setup = || -> Result<_, WatcherError> {
+        let display = Display::connect_to_env()?;
+        let mut queue = display.create_event_queue();
+        let attached = display.attach(queue.token());
+        let globals = GlobalManager::new(&attached);
+        queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        // The primary selection was added in version 2
+        let version = match selection {
+            Selection::Clipboard => 1,
+            Selection::Primary => 2,
+        };
+        let manager = globals.instantiate_exact::<ZwlrDataControlManagerV1>(version)?;
+        let seat = globals.instantiate_range::<WlSeat>(1, 7)?;
+        let device = manager.get_data_device(&seat);
+        Ok((display, queue, device))
+    };
+
+    let (_display, mut queue, device) = match setup() {
+        Ok(setup) => {
+            let _ = ready_tx.send(Ok(()));
+            setup
+        }
+        Err(e) => {
+            let _ = ready_tx.send(Err(e));
+            return Ok(());
+        }
+    };
+    info!(
+        "watching the {:?} selection with wlr data-control",
+        selection
+    );
+
+    let mut current_offer: Option<ZwlrDataControlOfferV1> = None;
+    device.quick_assign(move |device, event, _| {
+        match event {
+            zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                // Mime types are read on demand by the tracker
+                id.quick_assign(|_, _, _| {});
+            }
+            zwlr_data_control_device_v1::Event::Selection { id }
+                if selection == Selection::Clipboard =>
+            {
+                if let Some(old) = current_offer.take() {
+                    old.destroy();
+                }
+                if id.is_some() {
+                    debug!("selection changed");
+                    if tx.send(SelectionChanged).is_err() {
+                        device.destroy();
+                    }
+                }
+                current_offer = id;
+            }
+            zwlr_data_control_device_v1::Event::PrimarySelection { id }
+                if selection == Selection::Primary =>
+            {
+                if let Some(old) = current_offer.take() {
+                    old.destroy();
+                }
+                if id.is_some() {
+                    debug!("primary selection changed");
+                    if tx.send(SelectionChanged).is_err() {
+                        device.destroy();
+                    }
+                }
+                current_offer = id;
+            }
+            // Offers for the selection that isn't watched are never read
+            zwlr_data_control_device_v1::Event::Selection { id: Some(offer) }
+            | zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                offer.destroy();
+            }
+            zwlr_data_control_device_v1::Event::Finished => {
+                device.destroy();
+            }
+            _ => {}
+        }
+    });
+
+    loop {
+        queue.dispatch(&mut (), |_, _, _| {})?;
+        if !device.as_ref().is_alive() {
+            info!("data-control device finished, no longer watching the selection");
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+mod owner;
+mod reader;
+
+use super::{BackendError, ClipboardBackend, MimeContent, SelectionChanged};
+use fast_clipboard::entry::Selection;
+use log::{error, info};
+use owner::SelectionOwner;
+use reader::SelectionReader;
+use std::{sync::Mutex, thread, time::Duration};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        xfixes::{self, ConnectionExt as _},
+        xproto::Atom,
+        Event,
+    },
+    rust_connection::RustConnection,
+};
+
+/// How long to wait for the selection owner to hand over content
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The X11 CLIPBOARD or PRIMARY selection
+pub struct X11Backend {
+    /// The X server connected to, the one DISPLAY names if None
+    display: Option<String>,
+    /// One read at a time, the replies arrive on the same window
+    reader: Mutex<SelectionReader>,
+    /// Serves the contents set by the daemon
+    owner: SelectionOwner,
+}
+
+impl X11Backend {
+    pub fn new(selection: Selection) -> Result<Self, BackendError> {
+        Self::connect(None, selection)
+    }
+
+    /// The selection on the X server display, the one DISPLAY names if None
+    pub fn connect(display: Option<&str>, selection: Selection) -> Result<Self, BackendError> {
+        let name = match selection {
+            Selection::Clipboard => "CLIPBOARD",
+            Selection::Primary => "PRIMARY",
+        };
+        Ok(X11Backend {
+            display: display.map(str::to_string),
+            reader: Mutex::new(SelectionReader::new(display, name)?),
+            owner: SelectionOwner::new(display, name)?,
+        })
+    }
+}
+
+impl ClipboardBackend for X11Backend {
+    fn offers(&self) -> Result<Vec<String>, BackendError> {
+        let reader = self.reader.lock().unwrap();
+        let bytes = reader.read("TARGETS", READ_TIMEOUT)?;
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| {
+                reader.atom_name(Atom::from_ne_bytes([
+                    chunk[0], chunk[1], chunk[2], chunk[3],
+                ]))
+            })
+            .collect()
+    }
+
+    fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+        self.reader.lock().unwrap().read(mime, READ_TIMEOUT)
+    }
+
+    fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+        self.owner.set(contents)
+    }
+
+    fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+        let (connection, screen) = RustConnection::connect(self.display.as_deref())
+            .map_err(|e| BackendError::Unavailable(e.to_string()))?;
+        let selection = self.reader.lock().unwrap().selection();
+        let root = connection.setup().roots[screen].root;
+        select_selection_input(&connection, root, selection)
+            .map_err(|e| BackendError::Unavailable(e.to_string()))?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("selection-watcher".to_string())
+            .spawn(move || watch_events(connection, tx))
+            .map_err(|e| BackendError::Unavailable(e.to_string()))?;
+        info!("watching the selection with xfixes");
+        Ok(rx)
+    }
+}
+
+fn select_selection_input(
+    connection: &RustConnection,
+    root: u32,
+    selection: Atom,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if connection
+        .extension_information(xfixes::X11_EXTENSION_NAME)?
+        .is_none()
+    {
+        return Err("xfixes extension is not available".into());
+    }
+    connection.xfixes_query_version(5, 0)?.reply()?;
+    connection
+        .xfixes_select_selection_input(
+            root,
+            selection,
+            xfixes::SelectionEventMask::SET_SELECTION_OWNER
+                | xfixes::SelectionEventMask::SELECTION_CLIENT_CLOSE
+                | xfixes::SelectionEventMask::SELECTION_WINDOW_DESTROY,
+        )?
+        .check()?;
+    connection.flush()?;
+    Ok(())
+}
+
+fn watch_events(connection: RustConnection, tx: UnboundedSender<SelectionChanged>) {
+    loop {
+        match connection.wait_for_event() {
+            Ok(Event::XfixesSelectionNotify(event)) => {
+                // The selection is gone, nothing to read
+                if event.owner == x11rb::NONE {
+                    continue;
+                }
+                if tx.send(SelectionChanged).is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("selection watcher stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::{Child, Command};
+
+    /// A throwaway X server, killed when dropped
+    struct Xvfb(Child);
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Starts Xvfb, returned with the display it serves
+    fn xvfb() -> (Xvfb, String) {
+        let display = format!(":{}", 90 + std::process::id() % 100);
+        let xvfb = Xvfb(
+            Command::new("Xvfb")
+                .args([&display, "-nolisten", "tcp"])
+                .spawn()
+                .expect("Xvfb has to be installed"),
+        );
+        for _ in 0..50 {
+            if RustConnection::connect(Some(&display)).is_ok() {
+                return (xvfb, display);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("Xvfb did not start on {}", display);
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn test_x11_backend_serves_every_target() {
+        let (_xvfb, display) = xvfb();
+        let owner = X11Backend::connect(Some(&display), Selection::Clipboard).unwrap();
+        let reader = X11Backend::connect(Some(&display), Selection::Clipboard).unwrap();
+        // Too large for one property, sent in chunks
+        let text = "copied ".repeat(100_000);
+        owner
+            .set_selection(vec![
+                MimeContent::new("text/plain;charset=utf-8", text.as_bytes()),
+                MimeContent::new("text/html", b"<b>copied</b>"),
+            ])
+            .unwrap();
+
+        let offers = reader.offers().unwrap();
+        for target in [
+            "TARGETS",
+            "text/plain;charset=utf-8",
+            "text/html",
+            "UTF8_STRING",
+            "STRING",
+            "TEXT",
+        ] {
+            assert!(offers.iter().any(|offer| offer == target), "{}", target);
+        }
+        assert_eq!(reader.read("UTF8_STRING").unwrap(), text.as_bytes());
+        assert_eq!(reader.read("text/html").unwrap(), b"<b>copied</b>");
+    }
+}
@@ -0,0 +1,337 @@
+use crate::backend::{BackendError, MimeContent};
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+};
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        xproto::{
+            Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt as _, CreateWindowAux,
+            EventMask, PropMode, Property, SelectionNotifyEvent, SelectionRequestEvent, Window,
+            WindowClass, SELECTION_NOTIFY_EVENT,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    COPY_DEPTH_FROM_PARENT, CURRENT_TIME,
+};
+
+/// Bytes sent per property change when content is too large for a single one
+const INCR_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Legacy X11 text targets, served with the content of the first text/plain mime type
+/// when contents don't carry them already
+const TEXT_TARGETS: [&str; 3] = ["UTF8_STRING", "STRING", "TEXT"];
+
+fn write_error(e: impl std::fmt::Display) -> BackendError {
+    BackendError::Write(e.to_string())
+}
+
+/// Content served for one target
+struct Target {
+    atom: Atom,
+    /// The property type the content is sent as
+    kind: Atom,
+    bytes: Arc<Vec<u8>>,
+}
+
+/// A transfer of content too large for one property, sent in chunks
+/// each time the requestor deleted the previous one
+struct Incr {
+    kind: Atom,
+    bytes: Arc<Vec<u8>>,
+    sent: usize,
+}
+
+struct Atoms {
+    targets: Atom,
+    incr: Atom,
+    utf8_string: Atom,
+}
+
+/// Owns an X11 selection and serves every target of the contents set on it,
+/// answering TARGETS with the list of them
+pub struct SelectionOwner {
+    connection: Arc<RustConnection>,
+    window: Window,
+    selection: Atom,
+    atoms: Atoms,
+    /// What is served while the window owns the selection
+    served: Arc<Mutex<Vec<Target>>>,
+}
+
+impl SelectionOwner {
+    pub fn new(display: Option<&str>, selection: &str) -> Result<Self, BackendError> {
+        let unavailable = |e: &dyn std::fmt::Display| BackendError::Unavailable(e.to_string());
+        let (connection, screen) = RustConnection::connect(display).map_err(|e| unavailable(&e))?;
+        let root = connection.setup().roots[screen].root;
+        let window = connection.generate_id().map_err(|e| unavailable(&e))?;
+        connection
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                x11rb::COPY_FROM_PARENT,
+                &CreateWindowAux::new(),
+            )
+            .map_err(|e| unavailable(&e))?
+            .check()
+            .map_err(|e| unavailable(&e))?;
+        let intern = |name: &str| -> Result<Atom, BackendError> {
+            Ok(connection
+                .intern_atom(false, name.as_bytes())
+                .map_err(|e| unavailable(&e))?
+                .reply()
+                .map_err(|e| unavailable(&e))?
+                .atom)
+        };
+        let selection = intern(selection)?;
+        let atoms = Atoms {
+            targets: intern("TARGETS")?,
+            incr: intern("INCR")?,
+            utf8_string: intern("UTF8_STRING")?,
+        };
+
+        let owner = SelectionOwner {
+            connection: Arc::new(connection),
+            window,
+            selection,
+            atoms,
+            served: Arc::new(Mutex::new(vec![])),
+        };
+        let server = Server {
+            connection: owner.connection.clone(),
+            selection,
+            targets: owner.atoms.targets,
+            incr: owner.atoms.incr,
+            served: owner.served.clone(),
+            transfers: HashMap::new(),
+        };
+        thread::Builder::new()
+            .name("selection-owner".to_string())
+            .spawn(move || server.run())
+            .map_err(|e| unavailable(&e))?;
+        Ok(owner)
+    }
+
+    /// Takes the selection and serves contents until another client takes it
+    pub fn set(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+        if contents.is_empty() {
+            return Err(BackendError::Empty);
+        }
+        let targets = self.targets(contents)?;
+        *self.served.lock().unwrap() = targets;
+
+        self.connection
+            .set_selection_owner(self.window, self.selection, CURRENT_TIME)
+            .map_err(write_error)?
+            .check()
+            .map_err(write_error)?;
+        let owner = self
+            .connection
+            .get_selection_owner(self.selection)
+            .map_err(write_error)?
+            .reply()
+            .map_err(write_error)?
+            .owner;
+        if owner != self.window {
+            self.served.lock().unwrap().clear();
+            return Err(BackendError::Write(
+                "another client holds the selection".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// A target for every mime type, plus the legacy text targets missing from contents
+    fn targets(&self, contents: Vec<MimeContent>) -> Result<Vec<Target>, BackendError> {
+        let text = contents
+            .iter()
+            .find(|c| c.mime.starts_with("text/plain"))
+            .map(|c| Arc::new(c.bytes.clone()));
+        let mut targets = vec![];
+        for content in contents {
+            let atom = self.atom(&content.mime)?;
+            targets.push(Target {
+                atom,
+                kind: atom,
+                bytes: Arc::new(content.bytes),
+            });
+        }
+        if let Some(text) = text {
+            for name in TEXT_TARGETS {
+                let atom = self.atom(name)?;
+                if targets.iter().any(|target| target.atom == atom) {
+                    continue;
+                }
+                targets.push(Target {
+                    atom,
+                    // TEXT lets the owner pick the encoding
+                    kind: if name == "TEXT" {
+                        self.atoms.utf8_string
+                    } else {
+                        atom
+                    },
+                    bytes: text.clone(),
+                });
+            }
+        }
+        Ok(targets)
+    }
+
+    fn atom(&self, name: &str) -> Result<Atom, BackendError> {
+        Ok(self
+            .connection
+            .intern_atom(false, name.as_bytes())
+            .map_err(write_error)?
+            .reply()
+            .map_err(write_error)?
+            .atom)
+    }
+}
+
+/// Answers the selection requests of other clients on the owner's connection
+struct Server {
+    connection: Arc<RustConnection>,
+    selection: Atom,
+    targets: Atom,
+    incr: Atom,
+    served: Arc<Mutex<Vec<Target>>>,
+    /// Transfers in chunks by requestor window and property
+    transfers: HashMap<(Window, Atom), Incr>,
+}
+
+impl Server {
+    fn run(mut self) {
+        loop {
+            let result = match self.connection.wait_for_event() {
+                Ok(Event::SelectionRequest(event)) if event.selection == self.selection => {
+                    self.request(event)
+                }
+                Ok(Event::SelectionClear(event)) if event.selection == self.selection => {
+                    debug!("another client took the selection");
+                    self.served.lock().unwrap().clear();
+                    Ok(())
+                }
+                Ok(Event::PropertyNotify(event)) if event.state == Property::DELETE => {
+                    self.next_chunk(event.window, event.atom)
+                }
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    error!("selection owner stopped: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = result.and_then(|_| self.connection.flush().map_err(Into::into)) {
+                error!("could not serve the selection: {}", e);
+            }
+        }
+    }
+
+    fn request(&mut self, event: SelectionRequestEvent) -> Result<(), Box<dyn std::error::Error>> {
+        // Obsolete clients leave the property to the owner
+        let property = if event.property == x11rb::NONE {
+            event.target
+        } else {
+            event.property
+        };
+        let served = self.served.lock().unwrap();
+        let refused = if event.target == self.targets {
+            let mut atoms = vec![self.targets];
+            atoms.extend(served.iter().map(|target| target.atom));
+            self.connection.change_property32(
+                PropMode::REPLACE,
+                event.requestor,
+                property,
+                AtomEnum::ATOM,
+                &atoms,
+            )?;
+            served.is_empty()
+        } else if let Some(target) = served.iter().find(|target| target.atom == event.target) {
+            let max_len = self.connection.maximum_request_bytes() - 64;
+            if target.bytes.len() < max_len {
+                self.connection.change_property8(
+                    PropMode::REPLACE,
+                    event.requestor,
+                    property,
+                    target.kind,
+                    &target.bytes,
+                )?;
+            } else {
+                self.connection.change_window_attributes(
+                    event.requestor,
+                    &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+                )?;
+                self.connection.change_property32(
+                    PropMode::REPLACE,
+                    event.requestor,
+                    property,
+                    self.incr,
+                    &[target.bytes.len() as u32],
+                )?;
+                self.transfers.insert(
+                    (event.requestor, property),
+                    Incr {
+                        kind: target.kind,
+                        bytes: target.bytes.clone(),
+                        sent: 0,
+                    },
+                );
+            }
+            false
+        } else {
+            true
+        };
+        drop(served);
+        self.connection.send_event(
+            false,
+            event.requestor,
+            EventMask::NO_EVENT,
+            SelectionNotifyEvent {
+                response_type: SELECTION_NOTIFY_EVENT,
+                sequence: 0,
+                time: event.time,
+                requestor: event.requestor,
+                selection: event.selection,
+                target: event.target,
+                property: if refused { x11rb::NONE } else { property },
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Sends the next chunk once the requestor read the last one,
+    /// an empty chunk ends the transfer
+    fn next_chunk(
+        &mut self,
+        window: Window,
+        property: Atom,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(transfer) = self.transfers.get_mut(&(window, property)) else {
+            return Ok(());
+        };
+        let len = INCR_CHUNK_SIZE.min(transfer.bytes.len() - transfer.sent);
+        self.connection.change_property8(
+            PropMode::REPLACE,
+            window,
+            property,
+            transfer.kind,
+            &transfer.bytes[transfer.sent..transfer.sent + len],
+        )?;
+        transfer.sent += len;
+        if len == 0 {
+            self.transfers.remove(&(window, property));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::backend::BackendError;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            Atom, AtomEnum, ConnectionExt as _, CreateWindowAux, EventMask, GetPropertyReply,
+            Property, Window, WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    COPY_DEPTH_FROM_PARENT, CURRENT_TIME,
+};
+
+/// How long to sleep between polls while the selection owner is answering
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The property owners are asked to put the content in
+const PROPERTY_NAME: &str = "FASTCLIPD_SELECTION";
+
+fn read_error(e: impl std::fmt::Display) -> BackendError {
+    BackendError::Read(e.to_string())
+}
+
+/// Reads an X11 selection into a window of its own,
+/// receiving content too large for one property in chunks
+pub struct SelectionReader {
+    connection: RustConnection,
+    window: Window,
+    selection: Atom,
+    property: Atom,
+    incr: Atom,
+}
+
+impl SelectionReader {
+    pub fn new(display: Option<&str>, selection: &str) -> Result<Self, BackendError> {
+        let unavailable = |e: &dyn std::fmt::Display| BackendError::Unavailable(e.to_string());
+        let (connection, screen) = RustConnection::connect(display).map_err(|e| unavailable(&e))?;
+        let root = connection.setup().roots[screen].root;
+        let window = connection.generate_id().map_err(|e| unavailable(&e))?;
+        connection
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                x11rb::COPY_FROM_PARENT,
+                // Chunks are announced by changes of the property
+                &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+            )
+            .map_err(|e| unavailable(&e))?
+            .check()
+            .map_err(|e| unavailable(&e))?;
+        let intern = |name: &str| -> Result<Atom, BackendError> {
+            Ok(connection
+                .intern_atom(false, name.as_bytes())
+                .map_err(|e| unavailable(&e))?
+                .reply()
+                .map_err(|e| unavailable(&e))?
+                .atom)
+        };
+        Ok(SelectionReader {
+            selection: intern(selection)?,
+            property: intern(PROPERTY_NAME)?,
+            incr: intern("INCR")?,
+            connection,
+            window,
+        })
+    }
+
+    pub fn selection(&self) -> Atom {
+        self.selection
+    }
+
+    pub fn atom_name(&self, atom: Atom) -> Result<String, BackendError> {
+        let name = self
+            .connection
+            .get_atom_name(atom)
+            .map_err(read_error)?
+            .reply()
+            .map_err(read_error)?
+            .name;
+        Ok(String::from_utf8_lossy(&name).into_owned())
+    }
+
+    /// The content of the selection as target
+    /// Fails if its owner doesn't answer within timeout, or between two chunks
+    pub fn read(&self, target: &str, timeout: Duration) -> Result<Vec<u8>, BackendError> {
+        let owner = self
+            .connection
+            .get_selection_owner(self.selection)
+            .map_err(read_error)?
+            .reply()
+            .map_err(read_error)?
+            .owner;
+        if owner == x11rb::NONE {
+            return Err(BackendError::Empty);
+        }
+        let target_atom = self
+            .connection
+            .intern_atom(false, target.as_bytes())
+            .map_err(read_error)?
+            .reply()
+            .map_err(read_error)?
+            .atom;
+        self.connection
+            .convert_selection(
+                self.window,
+                self.selection,
+                target_atom,
+                self.property,
+                CURRENT_TIME,
+            )
+            .map_err(read_error)?
+            .check()
+            .map_err(read_error)?;
+
+        let mut deadline = Instant::now() + timeout;
+        let mut chunks: Option<Vec<u8>> = None;
+        loop {
+            let event = match self.connection.poll_for_event().map_err(read_error)? {
+                Some(event) => event,
+                None if Instant::now() >= deadline => {
+                    return Err(BackendError::Read(
+                        "the selection owner did not answer in time".to_string(),
+                    ))
+                }
+                None => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            match event {
+                Event::SelectionNotify(event)
+                    if event.selection == self.selection && chunks.is_none() =>
+                {
+                    if event.property == x11rb::NONE {
+                        return Err(BackendError::MimeNotOffered(target.to_string()));
+                    }
+                    let reply = self.take_property()?;
+                    if reply.type_ != self.incr {
+                        return Ok(reply.value);
+                    }
+                    // Taking the property asked the owner for the first chunk
+                    chunks = Some(vec![]);
+                    deadline = Instant::now() + timeout;
+                }
+                Event::PropertyNotify(event)
+                    if event.window == self.window
+                        && event.atom == self.property
+                        && event.state == Property::NEW_VALUE =>
+                {
+                    let Some(content) = chunks.as_mut() else {
+                        continue;
+                    };
+                    let reply = self.take_property()?;
+                    // An empty chunk ends the transfer
+                    if reply.value.is_empty() {
+                        return Ok(chunks.unwrap_or_default());
+                    }
+                    content.extend_from_slice(&reply.value);
+                    deadline = Instant::now() + timeout;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads and deletes the property the owner wrote to
+    fn take_property(&self) -> Result<GetPropertyReply, BackendError> {
+        self.connection
+            .get_property(true, self.window, self.property, AtomEnum::ANY, 0, u32::MAX)
+            .map_err(read_error)?
+            .reply()
+            .map_err(read_error)
+    }
+}
@@ -0,0 +1,37 @@
+use crate::server::default_addr;
+use anyhow::anyhow;
+use jsonrpsee::{core::client::ClientT, rpc_params, ws_client::WsClientBuilder};
+use std::io::BufRead;
+
+const USAGE: &str = "usage: daemon [rotate-key [--passphrase-stdin]]";
+
+/// Runs a command against the running daemon
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["rotate-key"] => rotate_key(None).await,
+        ["rotate-key", "--passphrase-stdin"] => rotate_key(Some(read_passphrase()?)).await,
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+/// Re-encrypts the history with a new key
+async fn rotate_key(passphrase: Option<String>) -> anyhow::Result<()> {
+    let client = WsClientBuilder::default()
+        .build(format!("ws://{}", default_addr()))
+        .await
+        .map_err(|e| anyhow!("could not connect to the daemon: {}", e))?;
+    let _: bool = client
+        .request("rotate_key", rpc_params![passphrase])
+        .await
+        .map_err(|e| anyhow!("could not rotate the key: {}", e))?;
+    println!("Rotated the encryption key");
+    Ok(())
+}
+
+/// Reads the first line of stdin, so the passphrase never shows up in the process list
+fn read_passphrase() -> anyhow::Result<String> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
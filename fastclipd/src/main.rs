@@ -1,32 +1,207 @@
+mod backend;
+mod cli;
 mod server;
 mod tracker;
 
-use log::{debug, info};
+use backend::ClipboardBackend;
+use fast_clipboard::{
+    config::KeyProtection,
+    entry::{Entry, EntryKind, Selection},
+    images,
+    sensitive::SensitiveKind,
+};
+use log::{debug, error, info};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::broadcast;
-use tracker::Tracker;
+use tracker::{selection_contents, Copied, MarkedBackend, Tracker};
+
+/// How often expired entries are looked for
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What happens to the content copied to a selection
+struct Tracking {
+    selection: Selection,
+    /// Whether the content is stored in the history
+    store: bool,
+    /// The other selection, made the same as this one if they are kept in sync
+    sync_to: Option<Arc<dyn ClipboardBackend>>,
+}
+
+/// Handles what the tracker reports until the selection can no longer be watched
+async fn track(
+    mut tracker: Tracker,
+    tracking: Tracking,
+    store: server::SharedStore,
+    tx: broadcast::Sender<Vec<u8>>,
+) {
+    while let Some(Copied {
+        content,
+        alternatives,
+        secret,
+        ..
+    }) = tracker.next().await
+    {
+        let mut entry = if images::is_image(&content.mime) {
+            match images::image_entry(&content.bytes, &content.mime) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!("Could not store copied image: {}", e);
+                    continue;
+                }
+            }
+        } else {
+            Entry::new(&content.bytes, EntryKind::Text).with_mime(&content.mime)
+        };
+        entry = entry.with_selection(tracking.selection);
+        for alternative in &alternatives {
+            entry = entry.with_alternative(&alternative.mime, &alternative.bytes);
+        }
+        // Detected once here, the history stores the entry as it is marked
+        entry = if secret {
+            entry.with_sensitive(SensitiveKind::PasswordManager)
+        } else {
+            entry.with_detected_sensitive()
+        };
+        // Sensitive content is left to the application that copied it
+        let sensitive = entry.sensitive().is_some();
+        let contents = selection_contents(&entry);
+        // Subscribers are only shown the mask of sensitive content
+        let announced = if sensitive {
+            entry.to_string().into_bytes()
+        } else {
+            content.bytes
+        };
+        if let Some(other) = tracking.sync_to.clone().filter(|_| !sensitive) {
+            let contents = contents.clone();
+            match tokio::task::spawn_blocking(move || other.set_selection(contents)).await {
+                Ok(Ok(())) => debug!("Synced the {:?} selection", tracking.selection),
+                Ok(Err(e)) => error!("Could not sync the selections: {}", e),
+                Err(e) => error!("Syncing the selections failed: {}", e),
+            }
+        }
+        if !tracking.store {
+            continue;
+        }
+        match server::ingest(&store, entry) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => {
+                error!("Could not store clipboard entry: {}", e);
+                continue;
+            }
+        }
+        // Taking over the primary selection would unselect the text it came from
+        if tracking.selection == Selection::Clipboard && !sensitive {
+            tracker.reown(contents).await;
+        }
+        debug!("Sending {} copied bytes to subscribers", announced.len());
+        // No subscribers is not an error for the daemon
+        let _ = tx.send(announced);
+    }
+    error!("Stopped tracking the {:?} selection", tracking.selection);
+}
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let home_path = home::home_dir().unwrap();
+    let dir_path = home_path.join(".config/fast_clipboard_manager");
+    let mut config = fast_clipboard::config::get_config(&dir_path)
+        .expect("Could not retrieve configuration file");
+    let store = match config.config.key_protection() {
+        KeyProtection::Passphrase => {
+            info!("History is locked until it is unlocked with its passphrase");
+            None
+        }
+        KeyProtection::Plain => {
+            let key_path = dir_path.join(fast_clipboard::key::KEY_FILE_NAME);
+            let key = match config.load_or_create_key(&key_path) {
+                Ok(key) => key,
+                Err(e) => {
+                    error!("Refusing to start without a valid encryption key: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let store = config
+                .open_history(key, None)
+                .expect("Could not load clipboard history");
+            server::report_recovery(&store);
+            Some(store)
+        }
+    };
+    let store = Arc::new(Mutex::new(store));
+
     let (tx, _rx) = broadcast::channel::<Vec<u8>>(16);
-    let tracker_tx = tx.clone();
     info!("Starting fastclipd");
+    let primary_config = config.config.primary_selection();
+    let password_hints = config.config.password_hint_policy();
+    let backend: Arc<dyn ClipboardBackend> = Arc::new(MarkedBackend::new(
+        backend::detect(Selection::Clipboard).expect("Could not find a clipboard to track"),
+    ));
+    let primary_backend = if primary_config.track || primary_config.sync {
+        match backend::detect(Selection::Primary) {
+            Ok(primary_backend) => {
+                Some(Arc::new(MarkedBackend::new(primary_backend)) as Arc<dyn ClipboardBackend>)
+            }
+            Err(e) => {
+                error!("Could not find a primary selection to track: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let tracker = Tracker::new(backend.clone())
+        .expect("Could not watch the clipboard")
+        .with_password_hints(password_hints);
+    let tracking = Tracking {
+        selection: Selection::Clipboard,
+        store: true,
+        sync_to: primary_backend.clone().filter(|_| primary_config.sync),
+    };
+    tokio::spawn(track(tracker, tracking, store.clone(), tx.clone()));
+
+    if let Some(primary_backend) = primary_backend {
+        match Tracker::new(primary_backend) {
+            Ok(tracker) => {
+                let tracker = tracker
+                    .with_password_hints(password_hints)
+                    .with_debounce(Duration::from_millis(primary_config.debounce_ms));
+                let tracking = Tracking {
+                    selection: Selection::Primary,
+                    store: primary_config.track,
+                    sync_to: Some(backend.clone()).filter(|_| primary_config.sync),
+                };
+                tokio::spawn(track(tracker, tracking, store.clone(), tx.clone()));
+            }
+            Err(e) => error!("Could not watch the primary selection: {}", e),
+        }
+    }
+
+    let expiry_store = store.clone();
     tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
         loop {
-            let tracker = Tracker::new();
-            let s = tracker.await;
-            debug!("Sending bytes from tracker: {:?}", s);
-            tracker_tx.send(s).unwrap();
+            interval.tick().await;
+            if let Err(e) = server::expire(&expiry_store) {
+                error!("Could not expire clipboard entries: {}", e);
+            }
         }
     });
 
-    let home_path = home::home_dir().unwrap();
-    let dir_path = home_path.join(".config/fast_clipboard_manager");
-    let config = fast_clipboard::config::get_config(&dir_path)
-        .expect("Could not retrieve configuration file");
-    let store = fast_clipboard::store::get_clipboard(&dir_path).unwrap();
-    let clip_mod = server::clip_module(config, store, tx).await;
+    let clip_mod = server::clip_module(config, store, backend, tx).await;
 
     info!("Fastclipd server starting");
     let (_addr, handle) = server::run_server(clip_mod).await.unwrap();
@@ -1,29 +1,229 @@
-use fast_clipboard::{config::ConfigFile, store::ClipboardStorage};
+use fast_clipboard::{
+    config::{ConfigFile, KeyProtection},
+    entry::{Entry, EntryId, EntryKind, Selection},
+    images::{self, ListedEntry, Thumbnail, THUMBNAIL_SIZE},
+    key::WRAPPED_KEY_FILE_NAME,
+    store::{ClipboardStorage, ClipboardStorageError, ListOrder},
+};
+
+use crate::backend::ClipboardBackend;
+use crate::tracker::selection_contents;
 
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
 use jsonrpsee::{
+    core::Error,
     server::{RpcModule, ServerBuilder, ServerHandle},
+    types::error::{CallError, ErrorObject},
     SubscriptionMessage,
 };
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use tokio::sync::broadcast::Sender;
 
 const DEFAULT_PORT: u64 = 22766;
 
+/// How many matches search_entries returns unless asked for a limit
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+/// Reported to clients calling methods that need the history while it is locked
+pub const LOCKED_ERROR_CODE: i32 = -32001;
+
+/// The ClipboardStorage shared between the tracker and the RPC server
+/// None while the history is locked
+pub type SharedStore = Arc<Mutex<Option<ClipboardStorage>>>;
+
 pub struct FastclipdContext {
-    pub config: ConfigFile,
-    pub store: ClipboardStorage,
+    pub config: Mutex<ConfigFile>,
+    pub store: SharedStore,
+    /// The clipboard selected entries are put on
+    pub backend: Arc<dyn ClipboardBackend>,
     pub tx: Sender<Vec<u8>>,
+    /// Thumbnails of the image entries listed so far, None for images that can't be decoded
+    pub thumbnails: Mutex<HashMap<EntryId, Option<Thumbnail>>>,
+}
+
+/// Stores tracked clipboard content, returns whether anything was stored
+/// Nothing is stored while the history is locked
+pub fn ingest(store: &SharedStore, entry: Entry) -> Result<bool, ClipboardStorageError> {
+    let mut store = store.lock().unwrap();
+    match store.as_mut() {
+        Some(store) => store.ingest_entry(entry),
+        None => {
+            debug!("history is locked, not storing clipboard content");
+            Ok(false)
+        }
+    }
+}
+
+/// Removes expired entries, returns how many there were
+/// Nothing expires while the history is locked, it does once it is unlocked
+pub fn expire(store: &SharedStore) -> Result<usize, ClipboardStorageError> {
+    match store.lock().unwrap().as_mut() {
+        Some(store) => store.expire(),
+        None => Ok(0),
+    }
+}
+
+/// Logs what was lost if opening the history fell back to its backup
+pub fn report_recovery(store: &ClipboardStorage) {
+    if let Some(recovery) = store.recovery() {
+        warn!(
+            "Clipboard history was restored from its backup: {}",
+            recovery
+        );
+    }
+}
+
+fn locked_error() -> Error {
+    CallError::Custom(ErrorObject::owned(
+        LOCKED_ERROR_CODE,
+        "clipboard history is locked",
+        None::<()>,
+    ))
+    .into()
+}
+
+fn call_error(e: impl Display) -> Error {
+    CallError::Failed(anyhow::anyhow!(e.to_string())).into()
+}
+
+impl FastclipdContext {
+    /// Decrypts the history with the key protected by the passphrase
+    fn unlock(&self, passphrase: &str) -> Result<(), Error> {
+        let config = self.config.lock().unwrap();
+        let key = config.unlock_key(passphrase).map_err(call_error)?;
+        let store = config
+            .open_history(key, Some(passphrase))
+            .map_err(call_error)?;
+        report_recovery(&store);
+        *self.store.lock().unwrap() = Some(store);
+        info!("history unlocked");
+        Ok(())
+    }
+
+    /// Forgets the decrypted history until the next unlock
+    fn lock(&self) -> Result<(), Error> {
+        let config = self.config.lock().unwrap();
+        if config.config.key_protection() != KeyProtection::Passphrase {
+            return Err(call_error(
+                "no passphrase is set, the history can't be locked",
+            ));
+        }
+        *self.store.lock().unwrap() = None;
+        info!("history locked");
+        Ok(())
+    }
+
+    /// Protects the key with a new passphrase, the history has to be unlocked
+    fn set_passphrase(&self, passphrase: &str) -> Result<(), Error> {
+        if passphrase.is_empty() {
+            return Err(call_error("the passphrase can't be empty"));
+        }
+        let key = match self.store.lock().unwrap().as_ref() {
+            Some(store) => *store.key(),
+            None => return Err(locked_error()),
+        };
+        let mut config = self.config.lock().unwrap();
+        let wrapped_path = config.dir().join(WRAPPED_KEY_FILE_NAME);
+        config
+            .protect_key(&key, passphrase, &wrapped_path)
+            .map_err(call_error)?;
+        info!("history is now protected by a passphrase");
+        Ok(())
+    }
+
+    /// Changes the history, which has to be unlocked
+    fn with_store<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut ClipboardStorage) -> Result<(), ClipboardStorageError>,
+    {
+        let mut store = self.store.lock().unwrap();
+        let store = store.as_mut().ok_or_else(locked_error)?;
+        f(store).map_err(call_error)
+    }
+
+    /// The entries as clients list them, each thumbnail is only made the first time
+    fn listed(
+        &self,
+        store: &mut ClipboardStorage,
+        entries: &[Entry],
+    ) -> Result<Vec<ListedEntry>, Error> {
+        let mut thumbnails = self.thumbnails.lock().unwrap();
+        // Some belong to entries gone from the history, the listed ones are made again
+        if thumbnails.len() > store.size().map_err(call_error)? {
+            thumbnails.clear();
+        }
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let thumbnail = match entry.kind() {
+                    EntryKind::Image => thumbnails
+                        .entry(entry.id())
+                        .or_insert_with(|| thumbnail(entry))
+                        .clone(),
+                    EntryKind::Text => None,
+                };
+                ListedEntry::new(entry, thumbnail)
+            })
+            .collect())
+    }
+
+    /// Makes the entry the selection, offering all its mime types,
+    /// and moves it to the front of the history
+    /// The history stays usable while the selection is set, which can block
+    fn select_entry(&self, id: EntryId) -> Result<(), Error> {
+        let contents = {
+            let mut store = self.store.lock().unwrap();
+            let store = store.as_mut().ok_or_else(locked_error)?;
+            let entry = store
+                .get_entry(id)
+                .map_err(call_error)?
+                .ok_or_else(|| call_error(format!("there is no entry with id {}", id)))?;
+            selection_contents(&entry)
+        };
+        self.backend.set_selection(contents).map_err(call_error)?;
+        self.with_store(|store| store.use_entry(id))
+    }
+
+    /// Re-encrypts the history with a new key, the history has to be unlocked
+    fn rotate_key(&self, passphrase: Option<&str>) -> Result<(), Error> {
+        let config = self.config.lock().unwrap();
+        let mut store = self.store.lock().unwrap();
+        let store = store.as_mut().ok_or_else(locked_error)?;
+        config.rotate_key(store, passphrase).map_err(call_error)
+    }
+}
+
+/// A thumbnail of the image entry, None if it can't be decoded
+fn thumbnail(entry: &Entry) -> Option<Thumbnail> {
+    match images::thumbnail(entry.content(), THUMBNAIL_SIZE) {
+        Ok(thumbnail) => Some(thumbnail),
+        Err(e) => {
+            info!("no thumbnail for image entry {}: {}", entry.id(), e);
+            None
+        }
+    }
 }
 
 pub async fn clip_module(
     config: ConfigFile,
-    store: ClipboardStorage,
+    store: SharedStore,
+    backend: Arc<dyn ClipboardBackend>,
     tx: Sender<Vec<u8>>,
 ) -> RpcModule<FastclipdContext> {
-    let ctx = FastclipdContext { config, store, tx };
+    let ctx = FastclipdContext {
+        config: Mutex::new(config),
+        store,
+        backend,
+        tx,
+        thumbnails: Mutex::new(HashMap::new()),
+    };
     let mut module = RpcModule::new(ctx);
 
     module
@@ -34,11 +234,145 @@ pub async fn clip_module(
         .unwrap();
 
     module
-        .register_method("get_entries", |_, ctx| {
+        .register_method("get_entries", |params, ctx| {
             info!("SERVER: get_entries");
-            let entries = ctx.store.list_entries();
-            let s = serde_json::to_string(entries).unwrap();
-            Ok(s)
+            // Optionally only a page of them, given as offset and limit, in another order
+            // and only those copied from one selection
+            let mut seq = params.sequence();
+            let offset: Option<usize> = seq.optional_next()?;
+            let limit: Option<usize> = seq.optional_next()?;
+            let order: Option<ListOrder> = seq.optional_next()?;
+            let selection: Option<Selection> = seq.optional_next()?;
+            let mut store = ctx.store.lock().unwrap();
+            let store = store.as_mut().ok_or_else(locked_error)?;
+            let (offset, limit) = (offset.unwrap_or(0), limit.unwrap_or(usize::MAX));
+            // Image entries are sent as thumbnails, selecting one puts the full image
+            // on the clipboard without it ever being sent
+            let entries = match order.unwrap_or_default() {
+                ListOrder::Recent => store.page(offset, limit, selection).map_err(call_error)?,
+                // Every entry has to be ranked before a page of them can be taken
+                ListOrder::Frecency => store
+                    .list(ListOrder::Frecency)
+                    .map_err(call_error)?
+                    .into_iter()
+                    .filter(|entry| selection.is_none_or(|s| entry.selection() == s))
+                    .skip(offset)
+                    .take(limit)
+                    .collect(),
+            };
+            let listed = ctx.listed(store, &entries)?;
+            Ok(serde_json::to_string(&listed).unwrap())
+        })
+        .unwrap();
+
+    module
+        .register_method("search_entries", |params, ctx| {
+            info!("SERVER: search_entries");
+            let mut seq = params.sequence();
+            let query: String = seq.next()?;
+            let limit: Option<usize> = seq.optional_next()?;
+            let mut store = ctx.store.lock().unwrap();
+            let store = store.as_mut().ok_or_else(locked_error)?;
+            let matches = store
+                .search(&query, limit.unwrap_or(DEFAULT_SEARCH_LIMIT))
+                .map_err(call_error)?;
+            Ok(serde_json::to_string(&matches).unwrap())
+        })
+        .unwrap();
+
+    module
+        .register_method("get_recovery", |_, ctx| {
+            info!("SERVER: get_recovery");
+            // What was lost if the history had to be restored from its backup, null if nothing
+            let store = ctx.store.lock().unwrap();
+            let store = store.as_ref().ok_or_else(locked_error)?;
+            Ok(serde_json::to_string(&store.recovery()).unwrap())
+        })
+        .unwrap();
+
+    module
+        .register_method("remove_entry", |params, ctx| {
+            info!("SERVER: remove_entry");
+            let id: EntryId = params.one()?;
+            ctx.with_store(|store| store.remove_entry(id))?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_blocking_method("select_entry", |params, ctx| {
+            info!("SERVER: select_entry");
+            let id: EntryId = params.one()?;
+            ctx.select_entry(id)?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_method("pin_entry", |params, ctx| {
+            info!("SERVER: pin_entry");
+            let id: EntryId = params.one()?;
+            ctx.with_store(|store| store.pin(id))?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_method("unpin_entry", |params, ctx| {
+            info!("SERVER: unpin_entry");
+            let id: EntryId = params.one()?;
+            ctx.with_store(|store| store.unpin(id))?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_method("clear_entries", |_, ctx| {
+            info!("SERVER: clear_entries");
+            ctx.with_store(|store| store.clear())?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_method("is_locked", |_, ctx| {
+            info!("SERVER: is_locked");
+            Ok(ctx.store.lock().unwrap().is_none())
+        })
+        .unwrap();
+
+    module
+        .register_blocking_method("unlock", |params, ctx| {
+            info!("SERVER: unlock");
+            let passphrase: String = params.one()?;
+            ctx.unlock(&passphrase)?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_method("lock", |_, ctx| {
+            info!("SERVER: lock");
+            ctx.lock()?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_blocking_method("set_passphrase", |params, ctx| {
+            info!("SERVER: set_passphrase");
+            let passphrase: String = params.one()?;
+            ctx.set_passphrase(&passphrase)?;
+            Ok(true)
+        })
+        .unwrap();
+
+    module
+        .register_blocking_method("rotate_key", |params, ctx| {
+            info!("SERVER: rotate_key");
+            let passphrase: Option<String> = params.sequence().optional_next()?;
+            ctx.rotate_key(passphrase.as_deref())?;
+            Ok(true)
         })
         .unwrap();
 
@@ -83,25 +417,68 @@ mod test {
     use jsonrpsee::{
         core::client::{ClientT, Subscription, SubscriptionClientT},
         rpc_params,
-        ws_client::WsClientBuilder,
+        ws_client::{WsClient, WsClientBuilder},
     };
     use log::debug;
-    use tokio::sync::broadcast;
+    use std::path::PathBuf;
+    use tokio::sync::{broadcast, mpsc::UnboundedReceiver};
 
     use super::*;
+    use crate::backend::{BackendError, MemoryBackend, MimeContent, SelectionChanged};
+    use fast_clipboard::clock::ManualClock;
+    use fast_clipboard::entry::EntryKind;
+    use fast_clipboard::search::SearchMatch;
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_server_can_run() {
-        let (tx, _rx) = broadcast::channel::<Vec<u8>>(16);
-        let config = ConfigFile::default();
-        let store = ClipboardStorage::default();
+    fn text(s: &str) -> Entry {
+        Entry::new(s.as_bytes(), EntryKind::Text)
+    }
+
+    /// A history kept in memory only
+    fn memory_store() -> SharedStore {
+        Arc::new(Mutex::new(Some(ClipboardStorage::default())))
+    }
+
+    /// A new empty directory for a test, the test removes it when done
+    fn tmp_dir() -> PathBuf {
+        let tmp_file = temp_file::empty();
+        let dir = tmp_file.path().with_extension("d");
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
 
-        let clip_mod = clip_module(config, store, tx).await;
+    /// Runs the server with a client connected to it,
+    /// along with the sender of what subscribers are told about
+    async fn serve(
+        config: ConfigFile,
+        store: SharedStore,
+        backend: Arc<dyn ClipboardBackend>,
+    ) -> (WsClient, ServerHandle, broadcast::Sender<Vec<u8>>) {
+        let (tx, _rx) = broadcast::channel::<Vec<u8>>(16);
+        let clip_mod = clip_module(config, store, backend, tx.clone()).await;
         let (addr, handle) = run_server(clip_mod).await.unwrap();
         let client = WsClientBuilder::default()
             .build(format!("ws://{}", &addr))
             .await
             .unwrap();
+        (client, handle, tx)
+    }
+
+    /// Like serve, with the default config and a clipboard in memory
+    async fn serve_in_memory(
+        store: &SharedStore,
+    ) -> (WsClient, ServerHandle, broadcast::Sender<Vec<u8>>) {
+        serve(
+            ConfigFile::default(),
+            store.clone(),
+            Arc::new(MemoryBackend::new()),
+        )
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_server_can_run() {
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
         let response: String = client.request("ping", rpc_params![]).await.unwrap();
         assert_eq!(response, "pong");
         handle.stop().unwrap();
@@ -109,16 +486,8 @@ mod test {
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_can_receive_clipboard_messages() {
-        let (tx, _rx) = broadcast::channel::<Vec<u8>>(16);
-        let config = ConfigFile::default();
-        let store = ClipboardStorage::default();
-
-        let clip_mod = clip_module(config, store, tx.clone()).await;
-        let (addr, handle) = run_server(clip_mod).await.unwrap();
-        let client = WsClientBuilder::default()
-            .build(format!("ws://{}", &addr))
-            .await
-            .unwrap();
+        let store = memory_store();
+        let (client, handle, tx) = serve_in_memory(&store).await;
         let sub: Subscription<Vec<u8>> = client
             .subscribe("subscribe_entry", rpc_params![], "unsubscribe_entry")
             .await
@@ -136,18 +505,378 @@ mod test {
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_can_get_entries() {
-        let (tx, _rx) = broadcast::channel::<Vec<u8>>(16);
-        let config = ConfigFile::default();
-        let store = ClipboardStorage::default();
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
+        let response: String = client.request("get_entries", rpc_params![]).await.unwrap();
+        assert_eq!(response, "[]");
+        handle.stop().unwrap();
+    }
 
-        let clip_mod = clip_module(config, store, tx).await;
-        let (addr, handle) = run_server(clip_mod).await.unwrap();
-        let client = WsClientBuilder::default()
-            .build(format!("ws://{}", &addr))
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_entries_sees_ingested_entries() {
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
+        assert!(ingest(&store, text("Something copied")).unwrap());
+        assert!(!ingest(&store, text("")).unwrap());
+
+        let response: String = client.request("get_entries", rpc_params![]).await.unwrap();
+        let entries: Vec<fast_clipboard::entry::Entry> = serde_json::from_str(&response).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].content(), "Something copied".as_bytes());
+
+        assert!(ingest(&store, text("Something else")).unwrap());
+        let response: String = client
+            .request("get_entries", rpc_params![1, 1])
+            .await
+            .unwrap();
+        let page: Vec<fast_clipboard::entry::Entry> = serde_json::from_str(&response).unwrap();
+        assert_eq!(page, entries);
+
+        store
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .use_entry(entries[0].id())
+            .unwrap();
+        assert!(ingest(&store, text("Copied last")).unwrap());
+        let response: String = client
+            .request(
+                "get_entries",
+                rpc_params![None::<usize>, 1, ListOrder::Frecency],
+            )
+            .await
+            .unwrap();
+        let frecent: Vec<fast_clipboard::entry::Entry> = serde_json::from_str(&response).unwrap();
+        assert_eq!(frecent[0].content(), "Something copied".as_bytes());
+
+        let selected = text("Selected").with_selection(Selection::Primary);
+        assert!(ingest(&store, selected).unwrap());
+        let response: String = client
+            .request(
+                "get_entries",
+                rpc_params![
+                    None::<usize>,
+                    None::<usize>,
+                    None::<ListOrder>,
+                    Selection::Primary
+                ],
+            )
+            .await
+            .unwrap();
+        let primary: Vec<fast_clipboard::entry::Entry> = serde_json::from_str(&response).unwrap();
+        assert_eq!(primary.len(), 1);
+        assert_eq!(primary[0].content(), "Selected".as_bytes());
+        handle.stop().unwrap();
+    }
+
+    /// A single red pixel
+    const PNG: [u8; 70] = [
+        137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 1, 0, 0, 0, 1, 8, 6,
+        0, 0, 0, 31, 21, 196, 137, 0, 0, 0, 13, 73, 68, 65, 84, 120, 156, 99, 248, 207, 192, 240,
+        31, 0, 5, 0, 1, 255, 137, 153, 61, 29, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130,
+    ];
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_entries_lists_images_as_thumbnails() {
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
+        ingest(&store, images::image_entry(&PNG, "image/png").unwrap()).unwrap();
+        ingest(&store, text("Something copied")).unwrap();
+
+        for _ in 0..2 {
+            let response: String = client.request("get_entries", rpc_params![]).await.unwrap();
+            let listed: Vec<ListedEntry> = serde_json::from_str(&response).unwrap();
+            assert_eq!(listed[0].entry.content(), b"Something copied");
+            assert_eq!(listed[0].thumbnail, None);
+            assert!(listed[1].entry.content().is_empty());
+            let thumbnail = listed[1].thumbnail.as_ref().unwrap();
+            assert_eq!(thumbnail.rgba, [255, 0, 0, 255]);
+        }
+        handle.stop().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_search_entries_finds_matches() {
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
+        ingest(&store, text("cargo build")).unwrap();
+        ingest(&store, text("git commit")).unwrap();
+
+        let response: String = client
+            .request("search_entries", rpc_params!["cb"])
+            .await
+            .unwrap();
+        let matches: Vec<SearchMatch> = serde_json::from_str(&response).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].entry.content(), "cargo build".as_bytes());
+        assert_eq!(matches[0].positions, [0, 6]);
+        let response: String = client
+            .request("search_entries", rpc_params!["", 1])
+            .await
+            .unwrap();
+        let matches: Vec<SearchMatch> = serde_json::from_str(&response).unwrap();
+        assert_eq!(matches.len(), 1);
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn test_expire_removes_expired_entries() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let mut history = ClipboardStorage::default();
+        history.set_clock(Arc::new(clock.clone()));
+        history.set_ttl(Some(chrono::Duration::hours(24)));
+        let store = Arc::new(Mutex::new(Some(history)));
+        ingest(&store, text("Something copied")).unwrap();
+
+        clock.advance(chrono::Duration::hours(24));
+        assert_eq!(expire(&store).unwrap(), 1);
+        assert_eq!(store.lock().unwrap().as_mut().unwrap().size().unwrap(), 0);
+        assert_eq!(expire(&Arc::new(Mutex::new(None))).unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_select_entry_sets_selection() {
+        let store = memory_store();
+        let backend = Arc::new(MemoryBackend::new());
+        let (client, handle, _tx) =
+            serve(ConfigFile::default(), store.clone(), backend.clone()).await;
+        ingest(
+            &store,
+            text("link").with_alternative("text/html", b"<a href=\"/\">link</a>"),
+        )
+        .unwrap();
+        ingest(&store, text("Something copied")).unwrap();
+        let id = store
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .list_entries()
+            .unwrap()[1]
+            .id();
+
+        let selected: bool = client
+            .request("select_entry", rpc_params![id])
+            .await
+            .unwrap();
+        assert!(selected);
+        assert_eq!(backend.read("UTF8_STRING").unwrap(), b"link");
+        assert_eq!(
+            backend.read("text/html").unwrap(),
+            b"<a href=\"/\">link</a>"
+        );
+        let entries = store
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .list_entries()
+            .unwrap();
+        assert_eq!(entries[0].id(), id);
+        assert_eq!(entries[0].metadata().use_count, 1);
+
+        let missing: Result<bool, _> = client
+            .request("select_entry", rpc_params![EntryId::new()])
+            .await;
+        assert!(missing.is_err());
+        handle.stop().unwrap();
+    }
+
+    /// Fails to set the selection while the history is locked
+    struct StoreCheckingBackend {
+        inner: MemoryBackend,
+        store: SharedStore,
+    }
+
+    impl ClipboardBackend for StoreCheckingBackend {
+        fn offers(&self) -> Result<Vec<String>, BackendError> {
+            self.inner.offers()
+        }
+
+        fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+            self.inner.read(mime)
+        }
+
+        fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+            if self.store.try_lock().is_err() {
+                return Err(BackendError::Write("the history is locked".to_string()));
+            }
+            self.inner.set_selection(contents)
+        }
+
+        fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+            self.inner.watch()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_select_entry_releases_store_while_setting_selection() {
+        let store = memory_store();
+        let backend = Arc::new(StoreCheckingBackend {
+            inner: MemoryBackend::new(),
+            store: store.clone(),
+        });
+        let (client, handle, _tx) =
+            serve(ConfigFile::default(), store.clone(), backend.clone()).await;
+        ingest(&store, text("Something copied")).unwrap();
+        let id = store
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .list_entries()
+            .unwrap()[0]
+            .id();
+
+        let selected: bool = client
+            .request("select_entry", rpc_params![id])
             .await
             .unwrap();
+        assert!(selected);
+        assert_eq!(
+            backend.inner.read("UTF8_STRING").unwrap(),
+            b"Something copied"
+        );
+        handle.stop().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_pinned_entries_survive_clearing() {
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
+        ingest(&store, text("Keep me")).unwrap();
+        ingest(&store, text("Something copied")).unwrap();
+        let ids: Vec<EntryId> = store
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .list_entries()
+            .unwrap()
+            .iter()
+            .map(Entry::id)
+            .collect();
+        ingest(&store, text("Copied meanwhile")).unwrap();
+        let pinned: bool = client
+            .request("pin_entry", rpc_params![ids[1]])
+            .await
+            .unwrap();
+        assert!(pinned);
+        let missing: Result<bool, _> = client
+            .request("pin_entry", rpc_params![EntryId::new()])
+            .await;
+        assert!(missing.is_err());
+        let cleared: bool = client
+            .request("clear_entries", rpc_params![])
+            .await
+            .unwrap();
+        assert!(cleared);
+
+        let response: String = client.request("get_entries", rpc_params![]).await.unwrap();
+        let entries: Vec<fast_clipboard::entry::Entry> = serde_json::from_str(&response).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].content(), "Keep me".as_bytes());
+        assert!(entries[0].is_pinned());
+
+        let removed: bool = client
+            .request("remove_entry", rpc_params![ids[1]])
+            .await
+            .unwrap();
+        assert!(removed);
+        assert_eq!(store.lock().unwrap().as_mut().unwrap().size().unwrap(), 0);
+        handle.stop().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_locked_history_can_be_unlocked() {
+        let dir = tmp_dir();
+        let mut config = fast_clipboard::config::get_config(&dir).unwrap();
+        let key = config.load_or_create_key(&dir.join("key")).unwrap();
+        config
+            .protect_key(&key, "correct horse", &dir.join(WRAPPED_KEY_FILE_NAME))
+            .unwrap();
+        let store = Arc::new(Mutex::new(None));
+
+        let (client, handle, _tx) =
+            serve(config, store.clone(), Arc::new(MemoryBackend::new())).await;
+        let locked: bool = client.request("is_locked", rpc_params![]).await.unwrap();
+        assert!(locked);
+        let entries: Result<String, _> = client.request("get_entries", rpc_params![]).await;
+        assert!(entries.is_err());
+        assert!(!ingest(&store, text("Something copied")).unwrap());
+
+        let wrong: Result<bool, _> = client
+            .request("unlock", rpc_params!["battery staple"])
+            .await;
+        assert!(wrong.is_err());
+        let unlocked: bool = client
+            .request("unlock", rpc_params!["correct horse"])
+            .await
+            .unwrap();
+        assert!(unlocked);
         let response: String = client.request("get_entries", rpc_params![]).await.unwrap();
         assert_eq!(response, "[]");
+
+        let locked: bool = client.request("lock", rpc_params![]).await.unwrap();
+        assert!(locked);
+        assert!(store.lock().unwrap().is_none());
+        handle.stop().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_history_without_passphrase_cant_be_locked() {
+        let store = memory_store();
+        let (client, handle, _tx) = serve_in_memory(&store).await;
+        let res: Result<bool, _> = client.request("lock", rpc_params![]).await;
+        assert!(res.is_err());
+        assert!(store.lock().unwrap().is_some());
+        handle.stop().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_recovery_reports_restored_backup() {
+        let dir = tmp_dir();
+        let mut config = fast_clipboard::config::get_config(&dir).unwrap();
+        let key = config.load_or_create_key(&dir.join("key")).unwrap();
+        let store = Arc::new(Mutex::new(Some(config.open_history(key, None).unwrap())));
+        ingest(&store, text("Something copied")).unwrap();
+        ingest(&store, text("Something else")).unwrap();
+        std::fs::write(dir.join("entries.json"), b"{").unwrap();
+        let history = config.open_history(key, None).unwrap();
+        *store.lock().unwrap() = Some(history);
+
+        let (client, handle, _tx) = serve(config, store, Arc::new(MemoryBackend::new())).await;
+        let response: String = client.request("get_recovery", rpc_params![]).await.unwrap();
+        let recovery: Option<fast_clipboard::fs::Recovery> =
+            serde_json::from_str(&response).unwrap();
         handle.stop().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(recovery.unwrap().path, dir.join("entries.json"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_rotate_key_keeps_history_readable() {
+        let dir = tmp_dir();
+        let mut config = fast_clipboard::config::get_config(&dir).unwrap();
+        let key = config.load_or_create_key(&dir.join("key")).unwrap();
+        let history = config.open_history(key, None).unwrap();
+        let store = Arc::new(Mutex::new(Some(history)));
+        assert!(ingest(&store, text("Something copied")).unwrap());
+
+        let (client, handle, _tx) =
+            serve(config, store.clone(), Arc::new(MemoryBackend::new())).await;
+        let rotated: bool = client.request("rotate_key", rpc_params![]).await.unwrap();
+        assert!(rotated);
+        handle.stop().unwrap();
+
+        let config = fast_clipboard::config::get_config(&dir).unwrap();
+        let new_key = config.config.get_key().unwrap();
+        let reopened = config.open_history(new_key, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(new_key, key);
+        assert_eq!(store.lock().unwrap().as_ref().unwrap().key(), &new_key);
+        assert_eq!(reopened.unwrap().size().unwrap(), 1);
     }
 }
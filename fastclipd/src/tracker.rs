@@ -1,80 +1,588 @@
-use log::debug;
-use std::{
-    future::Future,
-    io::Read,
-    pin::Pin,
-    task::{Context, Poll},
-    thread,
-    time::Duration,
-};
-use tokio::sync::broadcast::Sender;
+use crate::backend::{BackendError, ClipboardBackend, MimeContent, SelectionChanged};
+use fast_clipboard::entry::{Entry, EntryKind};
+use fast_clipboard::images::{self, IMAGE_MIME_TYPES, PNG_MIME_TYPE};
+use fast_clipboard::sensitive::PasswordHintPolicy;
+use log::{debug, error};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
 
-use wl_clipboard_rs::paste::{get_contents, ClipboardType, MimeType, Seat};
+/// Text mime types in order of preference
+const TEXT_MIME_TYPES: [&str; 5] = [
+    "text/plain;charset=utf-8",
+    "UTF8_STRING",
+    "text/plain",
+    "STRING",
+    "TEXT",
+];
 
-pub type TrackerSender = Sender<Vec<u8>>;
+/// Offered by password managers like KeePassXC, with the content "secret"
+const PASSWORD_MANAGER_HINT: &str = "x-kde-passwordManagerHint";
 
+/// Offered along with every selection the daemon sets, with the ID of the entry
+const OWNER_MIME_TYPE: &str = "application/x-fastclipd-entry";
+
+/// Content of a new selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Copied {
+    pub content: MimeContent,
+    /// The other mime types the content was offered as, like text/html
+    pub alternatives: Vec<MimeContent>,
+    /// Whether the copying application marked the content as a secret
+    pub secret: bool,
+    /// Whether the selection was set by the daemon itself
+    own: bool,
+}
+
+/// Reports new clipboard content as soon as the selection changes
 pub struct Tracker {
+    backend: Arc<dyn ClipboardBackend>,
     current: Option<Vec<u8>>,
-    poll_interval_ms: u64,
+    events: UnboundedReceiver<SelectionChanged>,
+    /// Set when a change was taken from events without being read yet
+    missed_change: bool,
+    password_hints: PasswordHintPolicy,
+    /// How long the selection has to stay unchanged before it is read
+    debounce: Option<Duration>,
 }
 
-const POLL_INTERVAL: u64 = 1000;
+impl Tracker {
+    pub fn new(backend: Arc<dyn ClipboardBackend>) -> Result<Self, BackendError> {
+        let events = backend.watch()?;
+        Ok(Tracker {
+            current: Self::read_clipboard(backend.as_ref()).map(|copied| copied.content.bytes),
+            backend,
+            events,
+            missed_change: false,
+            password_hints: PasswordHintPolicy::default(),
+            debounce: None,
+        })
+    }
+
+    /// Decides what happens to selections password managers mark as secret
+    pub fn with_password_hints(mut self, policy: PasswordHintPolicy) -> Self {
+        self.password_hints = policy;
+        self
+    }
+
+    /// Only reads the selection once it stayed unchanged for debounce,
+    /// so selections still being dragged out are read once
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = Some(debounce);
+        self
+    }
 
-impl Default for Tracker {
-    fn default() -> Self {
-        Self {
-            current: None,
-            poll_interval_ms: POLL_INTERVAL,
+    /// Waits for the next selection whose content differs from the current one
+    /// Returns None once the selection can no longer be watched
+    /// Selections marked as secret are skipped unless the policy keeps them
+    pub async fn next(&mut self) -> Option<Copied> {
+        loop {
+            if !std::mem::take(&mut self.missed_change) {
+                self.events.recv().await?;
+            }
+            // Several quick copies only need one read
+            while self.events.try_recv().is_ok() {}
+            if let Some(debounce) = self.debounce {
+                while let Ok(event) = tokio::time::timeout(debounce, self.events.recv()).await {
+                    event?;
+                }
+            }
+
+            let backend = self.backend.clone();
+            let read =
+                tokio::task::spawn_blocking(move || Self::read_clipboard(backend.as_ref())).await;
+            let copied = match read {
+                Ok(Some(copied)) => copied,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("reading clipboard failed: {}", e);
+                    continue;
+                }
+            };
+            // Whoever set it already recorded the use of the entry, so ignoring it is
+            // the same as touching it: the server uses the entry it selects, and reown and
+            // syncing the other selection follow the copy that stored or touched it
+            if copied.own {
+                debug!("ignoring selection set by the daemon");
+                self.current = Some(copied.content.bytes);
+                continue;
+            }
+            if copied.secret && self.password_hints == PasswordHintPolicy::Skip {
+                debug!("skipping selection marked as secret");
+                continue;
+            }
+            if Some(&copied.content.bytes) != self.current.as_ref() {
+                self.current = Some(copied.content.bytes.clone());
+                return Some(copied);
+            }
         }
     }
-}
 
-impl Tracker {
-    pub fn new() -> Self {
-        let mut t = Tracker::default();
-        t.current = t.read();
-        t
+    /// Serves contents as the selection, so they can still be pasted once the copying
+    /// application exits. Nothing is served if the selection changed again meanwhile
+    /// The first of contents becomes the current content, our own offer is not reported
+    pub async fn reown(&mut self, contents: Vec<MimeContent>) {
+        if self.events.try_recv().is_ok() {
+            debug!("selection changed again, not taking it over");
+            self.missed_change = true;
+            return;
+        }
+        let Some(first) = contents.first() else {
+            return;
+        };
+        let current = first.bytes.clone();
+        let expected = self.current.clone();
+        let backend = self.backend.clone();
+        let set = tokio::task::spawn_blocking(move || {
+            // The events only tell about changes up to now, the selection itself
+            // tells about the ones still on their way
+            let content = Self::read_content(backend.as_ref()).map(|(_, content)| content.bytes);
+            if content != expected {
+                return Ok(false);
+            }
+            backend.set_selection(contents).map(|_| true)
+        });
+        match set.await {
+            Ok(Ok(true)) => {
+                debug!("took over the selection");
+                self.current = Some(current);
+            }
+            Ok(Ok(false)) => {
+                debug!("selection changed again, not taking it over");
+                self.missed_change = true;
+            }
+            Ok(Err(e)) => error!("could not take over the selection: {}", e),
+            Err(e) => error!("taking over the selection failed: {}", e),
+        }
     }
 
-    fn read(&self) -> Option<Vec<u8>> {
-        if let Some(bytes) = Self::read_clipboard() {
-            if Some(&bytes) != self.current.as_ref() {
-                return Some(bytes);
+    /// The offers of the selection and its content in the preferred mime type
+    /// Images are only taken from selections without text
+    fn read_content(backend: &dyn ClipboardBackend) -> Option<(Vec<String>, MimeContent)> {
+        let offers = backend.offers().ok()?;
+        let mime = TEXT_MIME_TYPES
+            .iter()
+            .chain(IMAGE_MIME_TYPES.iter())
+            .find(|mime| offers.iter().any(|offer| offer == *mime))?;
+        match backend.read(mime) {
+            Ok(bytes) => Some((offers, MimeContent::new(mime, &bytes))),
+            Err(e) => {
+                debug!("could not read {}: {}", mime, e);
+                None
             }
         }
-        None
     }
 
-    fn read_clipboard() -> Option<Vec<u8>> {
+    fn read_clipboard(backend: &dyn ClipboardBackend) -> Option<Copied> {
         debug!("reading clipboard");
-        let result = get_contents(ClipboardType::Regular, Seat::Unspecified, MimeType::Text);
-        match result {
-            Ok((mut pipe, _)) => {
-                let mut contents = vec![];
-                pipe.read_to_end(&mut contents).unwrap();
-                Some(contents)
+        let (offers, content) = Self::read_content(backend)?;
+        let secret = offers.iter().any(|offer| offer == PASSWORD_MANAGER_HINT)
+            && Self::marked_secret(backend);
+        let alternatives = offers
+            .iter()
+            .filter(|offer| is_alternative(offer, &content.mime))
+            .filter_map(|offer| match backend.read(offer) {
+                Ok(bytes) => Some(MimeContent::new(offer, &bytes)),
+                Err(e) => {
+                    debug!("could not read {}: {}", offer, e);
+                    None
+                }
+            })
+            .collect();
+        Some(Copied {
+            content,
+            alternatives,
+            secret,
+            own: offers.iter().any(|offer| offer == OWNER_MIME_TYPE),
+        })
+    }
+
+    /// Whether the password manager hint says secret
+    /// An unreadable hint is taken as one, rather than storing a password
+    fn marked_secret(backend: &dyn ClipboardBackend) -> bool {
+        match backend.read(PASSWORD_MANAGER_HINT) {
+            Ok(hint) => hint.trim_ascii() == b"secret",
+            Err(e) => {
+                debug!("could not read {}: {}", PASSWORD_MANAGER_HINT, e);
+                true
             }
-            _ => None,
         }
     }
 }
 
-impl Future for Tracker {
-    type Output = Vec<u8>;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if let Some(bytes) = self.read() {
-            let mut tracker = self.get_mut();
-            tracker.current = Some(bytes.clone());
-            Poll::Ready(bytes)
-        } else {
-            let duration = Duration::from_millis(self.poll_interval_ms);
-            let waker = cx.waker().clone();
-            thread::spawn(move || {
-                std::thread::sleep(duration);
-                waker.wake();
-            });
-            Poll::Pending
+/// Whether offer is worth keeping besides the content read as mime
+/// Other text encodings and image formats only repeat the content,
+/// X11 targets like TIMESTAMP are no content at all
+fn is_alternative(offer: &str, mime: &str) -> bool {
+    offer != mime
+        && offer.contains('/')
+        && !offer.starts_with("text/plain")
+        && !(images::is_image(mime) && images::is_image(offer))
+}
+
+/// Remembers what the daemon last made the selection, so its own selection is recognized
+/// on backends that serve fewer targets than they are given and drop the owner marker
+pub struct MarkedBackend {
+    inner: Arc<dyn ClipboardBackend>,
+    last_set: Mutex<Option<Arc<Vec<MimeContent>>>>,
+}
+
+impl MarkedBackend {
+    pub fn new(inner: Arc<dyn ClipboardBackend>) -> Self {
+        MarkedBackend {
+            inner,
+            last_set: Mutex::new(None),
+        }
+    }
+
+    /// The entry ID of the contents last set, if the selection still serves them
+    fn owner(&self, offers: &[String]) -> Option<Vec<u8>> {
+        let last_set = self.last_set.lock().unwrap().clone()?;
+        let id = last_set.iter().find(|c| c.mime == OWNER_MIME_TYPE)?;
+        let served = last_set.iter().find(|c| offers.contains(&c.mime))?;
+        let bytes = self.inner.read(&served.mime).ok()?;
+        (bytes == served.bytes).then(|| id.bytes.clone())
+    }
+}
+
+impl ClipboardBackend for MarkedBackend {
+    fn offers(&self) -> Result<Vec<String>, BackendError> {
+        let mut offers = self.inner.offers()?;
+        if !offers.iter().any(|offer| offer == OWNER_MIME_TYPE) && self.owner(&offers).is_some() {
+            offers.push(OWNER_MIME_TYPE.to_string());
+        }
+        Ok(offers)
+    }
+
+    fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+        match self.inner.read(mime) {
+            Err(BackendError::MimeNotOffered(_)) if mime == OWNER_MIME_TYPE => self
+                .owner(&self.inner.offers()?)
+                .ok_or_else(|| BackendError::MimeNotOffered(mime.to_string())),
+            read => read,
+        }
+    }
+
+    fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+        *self.last_set.lock().unwrap() = Some(Arc::new(contents.clone()));
+        self.inner.set_selection(contents)
+    }
+
+    fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+        self.inner.watch()
+    }
+}
+
+/// What to offer when the entry is made the selection again,
+/// text under every text mime type and its alternatives as they were copied,
+/// marked so the tracker doesn't take it for a new copy
+pub fn selection_contents(entry: &Entry) -> Vec<MimeContent> {
+    let mut contents = match entry.kind() {
+        EntryKind::Text => TEXT_MIME_TYPES
+            .iter()
+            .map(|mime| MimeContent::new(mime, entry.content()))
+            .collect(),
+        EntryKind::Image => vec![MimeContent::new(PNG_MIME_TYPE, entry.content())],
+    };
+    contents.extend(
+        entry
+            .alternatives()
+            .map(|(mime, bytes)| MimeContent::new(mime, bytes)),
+    );
+    contents.push(MimeContent::new(
+        OWNER_MIME_TYPE,
+        entry.id().to_string().as_bytes(),
+    ));
+    contents
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{MemoryBackend, MimeContent};
+
+    fn tracker() -> (Arc<MemoryBackend>, Tracker) {
+        let backend = Arc::new(MemoryBackend::new());
+        let tracker = Tracker::new(backend.clone()).unwrap();
+        (backend, tracker)
+    }
+
+    #[tokio::test]
+    async fn test_tracker_reports_copied_text() {
+        let (backend, mut tracker) = tracker();
+        backend.copy_text("Something copied");
+        let copied = tracker.next().await.unwrap();
+        assert_eq!(copied.content.bytes, b"Something copied");
+        assert_eq!(copied.content.mime, "text/plain;charset=utf-8");
+        assert!(!copied.secret);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_skips_unchanged_selection() {
+        let (backend, mut tracker) = tracker();
+        backend.copy_text("first");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"first");
+        backend.copy_text("first");
+        backend.copy_text("second");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"second");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_ignores_selection_without_text_or_image() {
+        let (backend, mut tracker) = tracker();
+        backend.copy(vec![MimeContent::new("application/pdf", &[1, 2, 3])]);
+        let next = tokio::time::timeout(Duration::from_millis(50), tracker.next()).await;
+        assert!(next.is_err());
+        backend.copy(vec![MimeContent::new("UTF8_STRING", b"text")]);
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"text");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_prefers_utf8_text() {
+        let (backend, mut tracker) = tracker();
+        backend.copy(vec![
+            MimeContent::new("STRING", b"latin"),
+            MimeContent::new("text/plain;charset=utf-8", b"utf8"),
+        ]);
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"utf8");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_reports_copied_image() {
+        let (backend, mut tracker) = tracker();
+        backend.copy(vec![
+            MimeContent::new("image/bmp", &[4, 5, 6]),
+            MimeContent::new("image/png", &[1, 2, 3]),
+        ]);
+        assert_eq!(
+            tracker.next().await.unwrap().content,
+            MimeContent::new("image/png", &[1, 2, 3])
+        );
+        backend.copy(vec![
+            MimeContent::new("image/png", &[1, 2, 3]),
+            MimeContent::new("text/plain", b"alt text"),
+        ]);
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"alt text");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_keeps_alternative_representations() {
+        let (backend, mut tracker) = tracker();
+        backend.copy(vec![
+            MimeContent::new("TIMESTAMP", &[1]),
+            MimeContent::new("text/html", b"<b>bold</b>"),
+            MimeContent::new("text/plain", b"bold"),
+            MimeContent::new("text/plain;charset=utf-8", b"bold"),
+            MimeContent::new("text/uri-list", b"file:///tmp/bold"),
+        ]);
+        let copied = tracker.next().await.unwrap();
+        assert_eq!(copied.content.mime, "text/plain;charset=utf-8");
+        assert_eq!(
+            copied.alternatives,
+            [
+                MimeContent::new("text/html", b"<b>bold</b>"),
+                MimeContent::new("text/uri-list", b"file:///tmp/bold"),
+            ]
+        );
+
+        let entry =
+            Entry::new(b"bold", EntryKind::Text).with_alternative("text/html", b"<b>bold</b>");
+        let contents = selection_contents(&entry);
+        assert_eq!(contents.len(), TEXT_MIME_TYPES.len() + 2);
+        assert!(contents.contains(&MimeContent::new("UTF8_STRING", b"bold")));
+        assert!(contents.contains(&MimeContent::new("text/html", b"<b>bold</b>")));
+    }
+
+    #[tokio::test]
+    async fn test_tracker_reowns_selection_without_reporting_it() {
+        let (backend, mut tracker) = tracker();
+        backend.copy(vec![MimeContent::new("text/plain", b"kept")]);
+        let copied = tracker.next().await.unwrap();
+        let entry = Entry::new(&copied.content.bytes, EntryKind::Text);
+        tracker.reown(selection_contents(&entry)).await;
+        assert_eq!(backend.read("UTF8_STRING").unwrap(), b"kept");
+        let next = tokio::time::timeout(Duration::from_millis(50), tracker.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_does_not_reown_changed_selection() {
+        let (backend, mut tracker) = tracker();
+        backend.copy_text("first");
+        let copied = tracker.next().await.unwrap();
+        backend.copy_text("second");
+        tracker.reown(vec![copied.content]).await;
+        assert_eq!(backend.read("text/plain;charset=utf-8").unwrap(), b"second");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"second");
+    }
+
+    /// Lets another application copy at the moment the daemon reads the selection
+    /// or sets it, like a copy landing while the daemon takes the selection over
+    #[derive(Default)]
+    struct RacingBackend {
+        inner: MemoryBackend,
+        copy_on_read: Mutex<Option<Vec<MimeContent>>>,
+        copy_on_set: Mutex<Option<Vec<MimeContent>>>,
+    }
+
+    impl ClipboardBackend for RacingBackend {
+        fn offers(&self) -> Result<Vec<String>, BackendError> {
+            if let Some(contents) = self.copy_on_read.lock().unwrap().take() {
+                self.inner.copy(contents);
+            }
+            self.inner.offers()
         }
+
+        fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+            self.inner.read(mime)
+        }
+
+        fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+            self.inner.set_selection(contents)?;
+            if let Some(contents) = self.copy_on_set.lock().unwrap().take() {
+                self.inner.copy(contents);
+            }
+            Ok(())
+        }
+
+        fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+            self.inner.watch()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_keeps_selection_changed_while_reowning() {
+        let backend = Arc::new(RacingBackend::default());
+        let mut tracker = Tracker::new(backend.clone()).unwrap();
+        let kept = Entry::new(b"kept", EntryKind::Text);
+
+        backend.inner.copy_text("kept");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"kept");
+        let second = vec![MimeContent::new(TEXT_MIME_TYPES[0], b"second")];
+        *backend.copy_on_read.lock().unwrap() = Some(second.clone());
+        tracker.reown(selection_contents(&kept)).await;
+        assert!(backend.inner.read(OWNER_MIME_TYPE).is_err());
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"second");
+
+        backend.inner.copy_text("kept");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"kept");
+        *backend.copy_on_set.lock().unwrap() = Some(second);
+        tracker.reown(selection_contents(&kept)).await;
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"second");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_ignores_selection_set_by_daemon() {
+        let (backend, mut tracker) = tracker();
+        backend.copy_text("first");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"first");
+        let selected = Entry::new(b"selected", EntryKind::Text);
+        backend
+            .set_selection(selection_contents(&selected))
+            .unwrap();
+        assert_eq!(
+            backend.read(OWNER_MIME_TYPE).unwrap(),
+            selected.id().to_string().as_bytes()
+        );
+        let next = tokio::time::timeout(Duration::from_millis(50), tracker.next()).await;
+        assert!(next.is_err());
+        backend.copy_text("selected");
+        backend.copy_text("second");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"second");
+    }
+
+    /// Serves only the first of the contents it is given, like clipboard tools
+    /// that take a single target
+    struct SingleTargetBackend(MemoryBackend);
+
+    impl ClipboardBackend for SingleTargetBackend {
+        fn offers(&self) -> Result<Vec<String>, BackendError> {
+            self.0.offers()
+        }
+
+        fn read(&self, mime: &str) -> Result<Vec<u8>, BackendError> {
+            self.0.read(mime)
+        }
+
+        fn set_selection(&self, contents: Vec<MimeContent>) -> Result<(), BackendError> {
+            self.0.set_selection(contents.into_iter().take(1).collect())
+        }
+
+        fn watch(&self) -> Result<UnboundedReceiver<SelectionChanged>, BackendError> {
+            self.0.watch()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_ignores_daemon_selection_on_single_target_backend() {
+        let single = Arc::new(SingleTargetBackend(MemoryBackend::new()));
+        let backend = Arc::new(MarkedBackend::new(single.clone()));
+        let mut tracker = Tracker::new(backend.clone()).unwrap();
+        single.0.copy_text("first");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"first");
+
+        let selected = Entry::new(b"selected", EntryKind::Text);
+        backend
+            .set_selection(selection_contents(&selected))
+            .unwrap();
+        assert_eq!(single.0.offers().unwrap(), [TEXT_MIME_TYPES[0]]);
+        assert_eq!(
+            backend.read(OWNER_MIME_TYPE).unwrap(),
+            selected.id().to_string().as_bytes()
+        );
+        let next = tokio::time::timeout(Duration::from_millis(50), tracker.next()).await;
+        assert!(next.is_err());
+
+        single.0.copy_text("second");
+        assert!(backend.read(OWNER_MIME_TYPE).is_err());
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"second");
+    }
+
+    #[tokio::test]
+    async fn test_tracker_debounces_selection() {
+        let (backend, tracker) = tracker();
+        let mut tracker = tracker.with_debounce(Duration::from_millis(100));
+        let selecting = backend.clone();
+        tokio::spawn(async move {
+            for text in ["s", "se", "sel"] {
+                selecting.copy_text(text);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"sel");
+        backend.copy_text("next");
+        assert_eq!(tracker.next().await.unwrap().content.bytes, b"next");
+    }
+
+    fn copy_password(backend: &MemoryBackend, password: &str) {
+        backend.copy(vec![
+            MimeContent::new("text/plain;charset=utf-8", password.as_bytes()),
+            MimeContent::new(PASSWORD_MANAGER_HINT, b"secret"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_skips_selection_marked_secret() {
+        let (backend, mut tracker) = tracker();
+        copy_password(&backend, "hunter2");
+        let next = tokio::time::timeout(Duration::from_millis(50), tracker.next()).await;
+        assert!(next.is_err());
+        backend.copy(vec![
+            MimeContent::new("text/plain;charset=utf-8", b"not a secret"),
+            MimeContent::new(PASSWORD_MANAGER_HINT, b"public"),
+        ]);
+        let copied = tracker.next().await.unwrap();
+        assert_eq!(copied.content.bytes, b"not a secret");
+        assert!(!copied.secret);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_reports_secret_selection_if_configured() {
+        let (backend, tracker) = tracker();
+        let mut tracker = tracker.with_password_hints(PasswordHintPolicy::Sensitive);
+        copy_password(&backend, "hunter2");
+        let copied = tracker.next().await.unwrap();
+        assert_eq!(copied.content.bytes, b"hunter2");
+        assert!(copied.secret);
     }
 }